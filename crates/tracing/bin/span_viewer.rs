@@ -0,0 +1,282 @@
+use std::{collections::HashSet, env, fs, io, process, time::Duration};
+
+use holochain_tracing::{
+    export::jaeger,
+    query::SpanQuery,
+    tracer_console::{ConsoleReporter, RenderOptions, TreeStyle},
+    tracer_file::{self, RecordedSpan},
+};
+use rustracing_jaeger::span::TraceId;
+
+const USAGE: &str = "\
+Print recorded spans as a tree, without a Jaeger agent
+
+USAGE:
+    span-viewer [OPTIONS] FILE...
+
+Each FILE holds spans as JSON lines, as written by a file reporter, or a Jaeger
+JSON document. Spans from all files are merged, so traces which crossed several
+processes are shown whole, each span tagged with the service which recorded it.
+
+Filters select whole traces, those with a span matching all of them:
+    --trace ID            trace id, in hex
+    --name PATTERN        operation name, where * matches any characters
+    --min-duration MS     spans which took at least this many milliseconds
+
+Output:
+    --flat                don't group spans by trace
+    --timing              show offsets and durations
+    --slow MS             mark spans which took at least this long
+    --collapse            show runs of siblings with the same name as one line
+    --events              only show the logs of each span
+    --style STYLE         tabs, unicode or ascii
+    --no-color            don't color the unicode and ascii styles
+    -h, --help            show this message
+";
+
+struct Args {
+    files: Vec<String>,
+    query: Option<SpanQuery>,
+    options: RenderOptions,
+}
+
+/// For manually inspecting spans recorded on CI or by other processes
+fn main() {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let mut spans = Vec::new();
+    for file in args.files.iter() {
+        match read_file(file) {
+            Ok(read) => spans.extend(read),
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", file, e);
+                process::exit(1);
+            }
+        }
+    }
+    let spans = select(spans, args.query.as_ref());
+    let (span_tx, span_rx) = crossbeam_channel::unbounded();
+    for span in spans {
+        span_tx
+            .send(span.with_service_tag())
+            .expect("Receiver is alive");
+    }
+    let mut reporter = ConsoleReporter::new(span_rx);
+    reporter.drain();
+    reporter.print_with(&args.options);
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+    let mut files = Vec::new();
+    let mut query: Option<SpanQuery> = None;
+    let mut options = RenderOptions {
+        by_trace: true,
+        ..RenderOptions::for_terminal()
+    };
+    let mut no_color = false;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--trace" => {
+                let trace_id: TraceId = value()?
+                    .parse()
+                    .map_err(|_| "--trace needs a hex trace id".to_string())?;
+                query = Some(query.unwrap_or_default().trace_id(trace_id));
+            }
+            "--name" => query = Some(query.unwrap_or_default().name(value()?)),
+            "--min-duration" => {
+                let duration = parse_millis(&value()?)?;
+                query = Some(query.unwrap_or_default().min_duration(duration));
+            }
+            "--flat" => options.by_trace = false,
+            "--timing" => options.timing = true,
+            "--slow" => options.slow_threshold = Some(parse_millis(&value()?)?),
+            "--collapse" => options.collapse_repeated = true,
+            "--events" => options.only_events = true,
+            "--style" => {
+                options.style = match value()?.as_str() {
+                    "tabs" => TreeStyle::Tabs,
+                    "unicode" => TreeStyle::Unicode,
+                    "ascii" => TreeStyle::Ascii,
+                    other => return Err(format!("Unknown style {}", other)),
+                }
+            }
+            "--no-color" => no_color = true,
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        return Err("No files given".into());
+    }
+    options.color &= !no_color;
+    Ok(Args {
+        files,
+        query,
+        options,
+    })
+}
+
+fn parse_millis(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|ms| *ms >= 0.0)
+        .map(|ms| Duration::from_micros((ms * 1000.0) as u64))
+        .ok_or_else(|| format!("{} is not a number of milliseconds", value))
+}
+
+/// Either a Jaeger JSON document or JSON lines
+fn read_file(path: &str) -> io::Result<Vec<RecordedSpan>> {
+    let text = fs::read_to_string(path)?;
+    match serde_json::from_str::<serde_json::Value>(&text) {
+        Ok(doc) if doc.get("data").is_some() => jaeger::from_json(&doc),
+        _ => tracer_file::read_spans(text.as_bytes()),
+    }
+}
+
+/// The spans of the traces with a span matching `query`
+fn select(spans: Vec<RecordedSpan>, query: Option<&SpanQuery>) -> Vec<RecordedSpan> {
+    let query = match query {
+        Some(query) => query,
+        None => return spans,
+    };
+    let trace_id = |span: &RecordedSpan| span.span.context().state().trace_id();
+    let traces: HashSet<TraceId> = spans
+        .iter()
+        .filter(|span| query.matches(&span.span))
+        .map(trace_id)
+        .collect();
+    spans
+        .into_iter()
+        .filter(|span| traces.contains(&trace_id(span)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use holochain_tracing::{tracer_file::Rotation, Span, SpanContext};
+    use std::path::PathBuf;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse(&[
+            "--name",
+            "call*",
+            "--timing",
+            "--style",
+            "ascii",
+            "--no-color",
+            "a.jsonl",
+            "b.json",
+        ])
+        .unwrap();
+        assert_eq!(args.files, vec!["a.jsonl", "b.json"]);
+        assert!(args.query.is_some());
+        assert!(args.options.by_trace && args.options.timing && !args.options.color);
+        assert_eq!(args.options.style, TreeStyle::Ascii);
+
+        let args = parse(&["--flat", "--slow", "1.5", "a.jsonl"]).unwrap();
+        assert!(args.query.is_none());
+        assert!(!args.options.by_trace);
+        assert_eq!(
+            args.options.slow_threshold,
+            Some(Duration::from_micros(1500))
+        );
+
+        let error = |args: &[&str]| parse(args).err().unwrap();
+        assert_eq!(error(&[]), "No files given");
+        assert_eq!(error(&["a.jsonl", "--trace"]), "--trace needs a value");
+        assert_eq!(
+            error(&["--trace", "xyz", "a"]),
+            "--trace needs a hex trace id"
+        );
+        assert_eq!(
+            error(&["--min-duration", "-1", "a"]),
+            "-1 is not a number of milliseconds"
+        );
+        assert_eq!(error(&["--style", "round", "a"]), "Unknown style round");
+        assert_eq!(error(&["--bogus", "a"]), "Unknown option --bogus");
+    }
+
+    fn names(spans: &[RecordedSpan]) -> Vec<(String, String)> {
+        let mut names: Vec<_> = spans
+            .iter()
+            .map(|s| (s.service.clone(), s.span.operation_name().to_owned()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_select_merged_files() {
+        let dir = env::temp_dir().join(format!("span_viewer_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = |service: &str| dir.join(format!("{}.jsonl", service));
+        let (alice, alice_reporter) =
+            tracer_file::new_tracer_with_file_reporter("alice", path("alice"), Rotation::never())
+                .unwrap();
+        let (bob, bob_reporter) =
+            tracer_file::new_tracer_with_file_reporter("bob", path("bob"), Rotation::never())
+                .unwrap();
+        let (context, other_trace) = {
+            let call: Span = alice.span("call").start().into();
+            let other: Span = alice.span("other").start().into();
+            let other_trace = other.context().unwrap().0.state().trace_id();
+            (call.context().unwrap().encode().unwrap(), other_trace)
+        };
+        // The trace continues in another process
+        let context = SpanContext::decode(context).unwrap();
+        drop(context.follower(&bob, "handle"));
+        drop((alice, bob));
+        alice_reporter.join().unwrap();
+        bob_reporter.join().unwrap();
+        let read = || -> Vec<RecordedSpan> {
+            [path("alice"), path("bob")]
+                .iter()
+                .flat_map(|p: &PathBuf| read_file(p.to_str().unwrap()).unwrap())
+                .collect()
+        };
+        assert_eq!(read().len(), 3);
+
+        // A span matching in one file selects its whole trace from both
+        let query = SpanQuery::new().name("handle");
+        let selected = select(read(), Some(&query));
+        assert_eq!(
+            names(&selected),
+            vec![
+                ("alice".to_owned(), "call".to_owned()),
+                ("bob".to_owned(), "handle".to_owned()),
+            ]
+        );
+        let services: Vec<_> = selected
+            .into_iter()
+            .map(|s| s.with_service_tag().tags()[0].value().clone())
+            .collect();
+        assert!(services.contains(&"bob".into()));
+
+        let args = parse(&["--trace", &other_trace.to_string(), "x"]).unwrap();
+        let selected = select(read(), args.query.as_ref());
+        assert_eq!(
+            names(&selected),
+            vec![("alice".to_owned(), "other".to_owned())]
+        );
+        assert_eq!(select(read(), None).len(), 3);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
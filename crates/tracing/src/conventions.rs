@@ -0,0 +1,168 @@
+//! Standard tag keys, so that every part of holochain tags the same concepts
+//! the same way, and traces from different components can be searched alike.
+//! The `holochain.*` keys cover holochain concepts, the rest are the
+//! OpenTracing standard tags which Jaeger knows how to display.
+
+use crate::{display_tag, Span, Tag};
+use std::fmt::Display;
+
+pub const AGENT_ADDRESS: &str = "holochain.agent_address";
+pub const DNA_HASH: &str = "holochain.dna_hash";
+pub const ZOME_NAME: &str = "holochain.zome_name";
+pub const FUNCTION_NAME: &str = "holochain.function_name";
+pub const NETWORK_PEER: &str = "holochain.network_peer";
+pub const MESSAGE_KIND: &str = "holochain.message_kind";
+pub const THREAD_NAME: &str = "thread.name";
+
+pub const SPAN_KIND: &str = "span.kind";
+pub const PEER_ADDRESS: &str = "peer.address";
+pub const COMPONENT: &str = "component";
+pub const ERROR: &str = "error";
+
+/// Values of the OpenTracing `span.kind` tag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpanKind {
+    /// The client side of a request, e.g. an outgoing network request
+    Client,
+    /// The server side of a request, e.g. handling an incoming network request
+    Server,
+    /// Sending a message without waiting for a response
+    Producer,
+    /// Receiving a message which was sent without waiting for a response
+    Consumer,
+}
+
+impl SpanKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SpanKind::Client => "client",
+            SpanKind::Server => "server",
+            SpanKind::Producer => "producer",
+            SpanKind::Consumer => "consumer",
+        }
+    }
+}
+
+pub fn agent_address<D: Display>(address: D) -> Tag {
+    display_tag(AGENT_ADDRESS, address)
+}
+
+pub fn dna_hash<D: Display>(hash: D) -> Tag {
+    display_tag(DNA_HASH, hash)
+}
+
+pub fn zome_name<D: Display>(name: D) -> Tag {
+    display_tag(ZOME_NAME, name)
+}
+
+pub fn function_name<D: Display>(name: D) -> Tag {
+    display_tag(FUNCTION_NAME, name)
+}
+
+pub fn network_peer<D: Display>(peer: D) -> Tag {
+    display_tag(NETWORK_PEER, peer)
+}
+
+pub fn message_kind<D: Display>(kind: D) -> Tag {
+    display_tag(MESSAGE_KIND, kind)
+}
+
+pub fn thread_name<D: Display>(name: D) -> Tag {
+    display_tag(THREAD_NAME, name)
+}
+
+/// The name of the current thread, or its id if it has no name
+pub fn current_thread() -> Tag {
+    let thread = std::thread::current();
+    match thread.name() {
+        Some(name) => thread_name(name),
+        None => thread_name(format!("{:?}", thread.id())),
+    }
+}
+
+pub fn span_kind(kind: SpanKind) -> Tag {
+    Tag::new(SPAN_KIND, kind.as_str())
+}
+
+pub fn peer_address<D: Display>(address: D) -> Tag {
+    display_tag(PEER_ADDRESS, address)
+}
+
+pub fn component<D: Display>(component: D) -> Tag {
+    display_tag(COMPONENT, component)
+}
+
+/// Marks a span as failed. Jaeger highlights spans with this tag.
+pub fn error() -> Tag {
+    Tag::new(ERROR, true)
+}
+
+/// Tags for a call into a zome function
+pub fn zome_call<A, D, Z, F>(agent: A, dna: D, zome: Z, function: F) -> Vec<Tag>
+where
+    A: Display,
+    D: Display,
+    Z: Display,
+    F: Display,
+{
+    vec![
+        agent_address(agent),
+        dna_hash(dna),
+        zome_name(zome),
+        function_name(function),
+    ]
+}
+
+/// Tags for sending or receiving a network message
+pub fn network_message<P: Display, K: Display>(kind: SpanKind, peer: P, message: K) -> Vec<Tag> {
+    vec![span_kind(kind), network_peer(peer), message_kind(message)]
+}
+
+/// Set several tags on a span at once
+pub fn apply<I: IntoIterator<Item = Tag>>(span: &mut Span, tags: I) {
+    let tags: Vec<Tag> = tags.into_iter().collect();
+    span.set_tags(|| tags);
+}
+
+/// Tag a span as failed and log the error on it
+pub fn set_error<K, M>(span: &mut Span, kind: K, message: M)
+where
+    K: Into<std::borrow::Cow<'static, str>>,
+    M: Into<std::borrow::Cow<'static, str>>,
+{
+    span.set_tag(error);
+    let time = span.log_time();
+    span.0.log(|l| {
+        l.time(time).error().kind(kind).message(message);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustracing::tag::TagValue;
+
+    #[test]
+    fn test_apply() {
+        let (span_tx, span_rx) = crossbeam_channel::unbounded();
+        let tracer = crate::Tracer::with_sender(crate::AllSampler, span_tx);
+        {
+            let mut span: Span = tracer.span("call").start().into();
+            apply(&mut span, zome_call("agent", "dna", "zome", "fn"));
+            apply(&mut span, vec![component("conductor")]);
+            set_error(&mut span, "Timeout", "no response");
+        }
+        let span = span_rx.try_recv().unwrap();
+        let tag = |key| {
+            span.tags()
+                .iter()
+                .find(|t| t.name() == key)
+                .map(|t| t.value().clone())
+        };
+        assert_eq!(tag(ZOME_NAME), Some(TagValue::String("zome".into())));
+        assert_eq!(tag(COMPONENT), Some(TagValue::String("conductor".into())));
+        assert_eq!(tag(ERROR), Some(TagValue::Boolean(true)));
+        assert_eq!(span.tags().len(), 6);
+        assert_eq!(span.logs().len(), 1);
+    }
+}
@@ -0,0 +1,562 @@
+//! Byte-stable traces for snapshot tests. Rustracing draws span and trace ids
+//! from `rand` and timestamps from the system clock, so spans are started
+//! through a `DeterministicTracer` instead: ids come from an `IdGenerator` and
+//! timestamps from a `Clock`, read as spans start, log and finish. See
+//! `new_tracer_with_deterministic_console_reporter`.
+//!
+//! Spans started by a `DeterministicTracer` carry a baggage item naming their
+//! source, which their children and followers inherit, so spans started from
+//! them through this crate (`Span::child`, `SpanBuilder`, `SpanSnapshot`, ...)
+//! take their ids and start times from it too. Spans finish at the clock's time
+//! when dropped as a `DeterministicSpan` or popped off the span stack; any
+//! other span finishes on the system clock unless wrapped with
+//! `DeterministicSpan::from`.
+//!
+//! Spans started from a context decoded from the network find their source by
+//! trace id, since baggage isn't encoded, so tests which run in parallel and
+//! decode contexts should use differently seeded ids. Only the most recent
+//! `MAX_TRACES` traces of each tracer are found this way.
+//! Output is only stable if the spans are started in a stable order, e.g. on
+//! one thread.
+
+use crate::Tracer;
+use rustracing::sampler::Sampler;
+use rustracing::span::{BaggageItem, SpanContext, StartSpanOptions};
+use rustracing_jaeger::span::{SpanContextState, SpanContextStateBuilder, TraceId};
+use rustracing_jaeger::Span as RjSpan;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, SystemTime};
+
+/// The baggage item naming the source of a deterministic span
+const SOURCE_KEY: &str = "holochain_tracing.deterministic";
+
+/// Traces per `DeterministicTracer` which contexts without baggage can find
+pub const MAX_TRACES: usize = 1024;
+
+/// Counts sources, so spans need no lookups while there are none
+static LIVE_SOURCES: AtomicUsize = AtomicUsize::new(0);
+static NEXT_SOURCE: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref SOURCES: Mutex<HashMap<usize, Weak<Source>>> = Mutex::new(HashMap::new());
+    /// The source of each trace, for contexts which lost their baggage
+    static ref TRACES: Mutex<HashMap<TraceId, usize>> = Mutex::new(HashMap::new());
+}
+
+/// Source of span and trace ids
+#[derive(Clone, Debug)]
+pub enum IdGenerator {
+    /// Consecutive ids from the given one on
+    Sequential(u64),
+    /// Pseudo-random ids, the same sequence for the same seed
+    Seeded(u64),
+}
+
+impl IdGenerator {
+    /// Consecutive ids starting at 1
+    pub fn sequential() -> Self {
+        IdGenerator::Sequential(1)
+    }
+
+    /// Pseudo-random ids from `seed`
+    pub fn seeded(seed: u64) -> Self {
+        IdGenerator::Seeded(seed)
+    }
+
+    /// The next id. Never zero, which Jaeger reads as "no id".
+    pub fn next_id(&mut self) -> u64 {
+        match self {
+            IdGenerator::Sequential(next) => {
+                let id = (*next).max(1);
+                *next = id.wrapping_add(1);
+                id
+            }
+            IdGenerator::Seeded(state) => loop {
+                // splitmix64
+                *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                let mut z = *state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                z ^= z >> 31;
+                if z != 0 {
+                    break z;
+                }
+            },
+        }
+    }
+}
+
+impl Default for IdGenerator {
+    fn default() -> Self {
+        IdGenerator::sequential()
+    }
+}
+
+/// Source of timestamps, read whenever a deterministic span starts, logs or
+/// finishes
+pub trait Clock: Send {
+    fn now(&mut self) -> SystemTime;
+}
+
+impl<F: FnMut() -> SystemTime + Send> Clock for F {
+    fn now(&mut self) -> SystemTime {
+        self()
+    }
+}
+
+/// A clock which starts at `start` and advances by `step` on every reading
+#[derive(Clone, Debug)]
+pub struct SteppedClock {
+    next: SystemTime,
+    step: Duration,
+}
+
+impl SteppedClock {
+    pub fn new(start: SystemTime, step: Duration) -> Self {
+        SteppedClock { next: start, step }
+    }
+}
+
+impl Default for SteppedClock {
+    /// Starts at the Unix epoch and advances by a millisecond
+    fn default() -> Self {
+        SteppedClock::new(SystemTime::UNIX_EPOCH, Duration::from_millis(1))
+    }
+}
+
+impl Clock for SteppedClock {
+    fn now(&mut self) -> SystemTime {
+        let now = self.next;
+        self.next += self.step;
+        now
+    }
+}
+
+/// Starts root spans whose ids and times, and those of their descendants,
+/// come from an `IdGenerator` and a `Clock`
+#[derive(Clone)]
+pub struct DeterministicTracer {
+    tracer: Tracer,
+    source: Arc<Source>,
+}
+
+impl DeterministicTracer {
+    /// Constructor
+    pub fn new<C: Clock + 'static>(tracer: Tracer, ids: IdGenerator, clock: C) -> Self {
+        let key = NEXT_SOURCE.fetch_add(1, Ordering::SeqCst);
+        let source = Arc::new(Source {
+            key,
+            state: Mutex::new(SourceState {
+                ids,
+                clock: Box::new(clock),
+                traces: VecDeque::new(),
+            }),
+        });
+        lock(&SOURCES).insert(key, Arc::downgrade(&source));
+        LIVE_SOURCES.fetch_add(1, Ordering::SeqCst);
+        DeterministicTracer { tracer, source }
+    }
+
+    /// The underlying tracer, e.g. for starting spans from contexts.
+    /// Root spans started from it directly are not deterministic.
+    pub fn tracer(&self) -> &Tracer {
+        &self.tracer
+    }
+
+    /// Start a root span
+    pub fn span<N: Into<Cow<'static, str>>>(&self, operation_name: N) -> DeterministicSpan {
+        DeterministicSpan {
+            span: self
+                .source
+                .start(self.tracer.span(operation_name), None, None)
+                .into(),
+            source: Some(self.source.clone()),
+            finish_time: None,
+        }
+    }
+}
+
+/// A span which finishes at the time of its source's clock when dropped,
+/// unless given a finish time with `set_finish_time`
+pub struct DeterministicSpan {
+    span: crate::Span,
+    source: Option<Arc<Source>>,
+    finish_time: Option<SystemTime>,
+}
+
+impl DeterministicSpan {
+    /// Start a child, which also finishes on the clock
+    pub fn child<S: Into<Cow<'static, str>>>(&self, operation_name: S) -> Self {
+        self.wrap(self.span.child(operation_name))
+    }
+
+    /// Start a follower, which also finishes on the clock
+    pub fn follower<S: Into<Cow<'static, str>>>(&self, operation_name: S) -> Self {
+        self.wrap(self.span.follower(operation_name))
+    }
+
+    /// Finish at the time given by `f` instead. Setting the finish time through
+    /// the inner span has no effect, since it is overwritten on drop.
+    pub fn set_finish_time<F: FnOnce() -> SystemTime>(&mut self, f: F) {
+        self.finish_time = Some(f());
+    }
+
+    fn wrap(&self, span: crate::Span) -> Self {
+        DeterministicSpan {
+            span,
+            source: self.source.clone(),
+            finish_time: None,
+        }
+    }
+}
+
+impl From<crate::Span> for DeterministicSpan {
+    /// Wraps any span, which finishes on the clock if it has a deterministic source
+    fn from(span: crate::Span) -> Self {
+        let source = span.0.context().and_then(source_of);
+        DeterministicSpan {
+            span,
+            source,
+            finish_time: None,
+        }
+    }
+}
+
+impl Deref for DeterministicSpan {
+    type Target = crate::Span;
+
+    fn deref(&self) -> &crate::Span {
+        &self.span
+    }
+}
+
+impl DerefMut for DeterministicSpan {
+    fn deref_mut(&mut self) -> &mut crate::Span {
+        &mut self.span
+    }
+}
+
+impl Drop for DeterministicSpan {
+    fn drop(&mut self) {
+        let time = match (self.finish_time, &self.source) {
+            (Some(time), _) => time,
+            (None, Some(source)) => source.now(),
+            (None, None) => return,
+        };
+        self.span.0.set_finish_time(|| time);
+    }
+}
+
+impl std::fmt::Debug for DeterministicSpan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeterministicSpan")
+            .field("span", &self.span)
+            .field("source", &self.source.as_ref().map(|s| s.key))
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for DeterministicTracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeterministicTracer")
+            .field("source", &self.source.key)
+            .finish()
+    }
+}
+
+struct Source {
+    key: usize,
+    state: Mutex<SourceState>,
+}
+
+struct SourceState {
+    ids: IdGenerator,
+    clock: Box<dyn Clock>,
+    /// The traces registered in `TRACES`, oldest first
+    traces: VecDeque<TraceId>,
+}
+
+impl Source {
+    fn now(&self) -> SystemTime {
+        lock(&self.state).clock.now()
+    }
+
+    /// Start a span in the trace of `parent`, or in a new trace
+    fn start<S: Sampler<SpanContextState>>(
+        &self,
+        options: StartSpanOptions<'_, S, SpanContextState>,
+        parent: Option<&SpanContext<SpanContextState>>,
+        start_time: Option<SystemTime>,
+    ) -> RjSpan {
+        let (state, start_time) = {
+            let mut source = lock(&self.state);
+            let start_time = start_time.unwrap_or_else(|| source.clock.now());
+            let trace_id = match parent {
+                Some(parent) => parent.state().trace_id(),
+                None => {
+                    let trace_id = TraceId {
+                        high: 0,
+                        low: source.ids.next_id(),
+                    };
+                    let mut traces = lock(&TRACES);
+                    traces.insert(trace_id, self.key);
+                    source.traces.push_back(trace_id);
+                    while source.traces.len() > MAX_TRACES {
+                        let oldest = source.traces.pop_front().expect("longer than the limit");
+                        unregister(&mut traces, oldest, self.key);
+                    }
+                    trace_id
+                }
+            };
+            let state = SpanContextStateBuilder::new()
+                .trace_id(trace_id)
+                .span_id(source.ids.next_id())
+                .finish();
+            (state, start_time)
+        };
+        let mut span = options.start_time(start_time).start_with_state(state);
+        let key = self.key.to_string();
+        span.set_baggage_item(|| BaggageItem::new(SOURCE_KEY, &key));
+        span
+    }
+}
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        lock(&SOURCES).remove(&self.key);
+        let state = lock(&self.state);
+        let mut traces = lock(&TRACES);
+        for trace_id in state.traces.iter() {
+            unregister(&mut traces, *trace_id, self.key);
+        }
+        LIVE_SOURCES.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Forget the source of a trace, unless another source has taken it over
+fn unregister(traces: &mut HashMap<TraceId, usize>, trace_id: TraceId, key: usize) {
+    if traces.get(&trace_id) == Some(&key) {
+        traces.remove(&trace_id);
+    }
+}
+
+/// Ignore poisoning, since the maps stay consistent between statements
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn registered(key: usize) -> Option<Arc<Source>> {
+    lock(&SOURCES).get(&key).and_then(Weak::upgrade)
+}
+
+/// The source named by a context's baggage
+fn source_of(context: &SpanContext<SpanContextState>) -> Option<Arc<Source>> {
+    if LIVE_SOURCES.load(Ordering::SeqCst) == 0 {
+        return None;
+    }
+    let key = context
+        .baggage_items()
+        .iter()
+        .find(|item| item.name() == SOURCE_KEY)?
+        .value()
+        .parse()
+        .ok()?;
+    registered(key)
+}
+
+/// Start a span from `options`, taking its ids and start time from the
+/// deterministic source of `parent` if it has one. An explicit `start_time`
+/// is kept either way.
+pub(crate) fn start_span<S: Sampler<SpanContextState>>(
+    options: StartSpanOptions<'_, S, SpanContextState>,
+    parent: Option<&SpanContext<SpanContextState>>,
+    start_time: Option<SystemTime>,
+) -> RjSpan {
+    let source = parent.and_then(|parent| {
+        source_of(parent).or_else(|| {
+            if LIVE_SOURCES.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+            let key = *lock(&TRACES).get(&parent.state().trace_id())?;
+            registered(key)
+        })
+    });
+    match (source, start_time) {
+        (Some(source), _) => source.start(options, parent, start_time),
+        (None, Some(time)) => options.start_time(time).start(),
+        (None, None) => options.start(),
+    }
+}
+
+/// The time on the clock of a span's deterministic source, if it has one
+pub(crate) fn now(context: Option<&SpanContext<SpanContextState>>) -> Option<SystemTime> {
+    context.and_then(source_of).map(|source| source.now())
+}
+
+/// Finish a span at the time on its source's clock, if it has a source
+pub(crate) fn finish(span: &mut crate::Span) {
+    if let Some(time) = now(span.0.context()) {
+        span.0.set_finish_time(|| time);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer_console::new_tracer_with_deterministic_console_reporter;
+
+    fn run() -> String {
+        let (tracer, mut reporter) = new_tracer_with_deterministic_console_reporter(
+            IdGenerator::sequential(),
+            SteppedClock::default(),
+        );
+        {
+            let root = tracer.span("root");
+            let mut child = root.child("child");
+            child.set_tag(|| crate::Tag::new("b", 1));
+            child.set_tag(|| crate::Tag::new("a", 2));
+            child.event("hello");
+            let _follower = root.follower("follower");
+        }
+        reporter.drain();
+        let ids = reporter.with_spans(|spans| {
+            let mut ids: Vec<_> = spans
+                .map(|s| (s.operation_name().to_owned(), s.context().state().span_id()))
+                .collect();
+            ids.sort();
+            ids
+        });
+        format!(
+            "{:?}\n{}",
+            ids,
+            reporter.render_to_string_with(&crate::tracer_console::RenderOptions::with_timing())
+        )
+    }
+
+    #[test]
+    fn test_stable_output() {
+        let first = run();
+        assert_eq!(first, run());
+        assert_eq!(
+            first,
+            format!(
+                "{}\n{}\n{}\n{}\n{}\n",
+                "[(\"child\", 3), (\"follower\", 4), (\"root\", 2)]",
+                "    +0.000ms      6.000ms      [root] ",
+                "    +1.000ms      4.000ms      \t[child] {b = Integer(1)} {a = Integer(2)} ",
+                "                               \t!event! [+1.000ms] hello",
+                "    +3.000ms      1.000ms      [follower] ",
+            )
+        );
+    }
+
+    #[test]
+    fn test_live_spans() {
+        let (tracer, mut reporter) = new_tracer_with_deterministic_console_reporter(
+            IdGenerator::seeded(41),
+            SteppedClock::default(),
+        );
+        let mut expected = IdGenerator::seeded(41);
+        let trace_id = expected.next_id();
+        {
+            let root = tracer.span("root");
+            let context = root.context().unwrap();
+            assert_eq!(context.0.state().trace_id().low, trace_id);
+            assert_eq!(context.0.state().span_id(), expected.next_id());
+            // Baggage is lost on the way, so the source is found by trace id
+            let decoded = crate::SpanContext::decode(context.encode().unwrap()).unwrap();
+            let remote = decoded.follower(tracer.tracer(), "remote");
+            assert_eq!(
+                remote.context().unwrap().0.state().span_id(),
+                expected.next_id()
+            );
+            let _guard = crate::push_span(remote);
+            let pushed =
+                DeterministicSpan::from(crate::SpanBuilder::new("pushed").start().unwrap());
+            assert_eq!(
+                pushed.context().unwrap().0.state().span_id(),
+                expected.next_id()
+            );
+        }
+        reporter.drain();
+        let millis = |t: SystemTime| {
+            t.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+        };
+        let mut times = reporter.with_spans(|spans| {
+            spans
+                .map(|s| {
+                    let name = s.operation_name().to_owned();
+                    (millis(s.start_time()), millis(s.finish_time()), name)
+                })
+                .collect::<Vec<_>>()
+        });
+        times.sort();
+        assert_eq!(
+            times,
+            vec![
+                (0, 5, "root".to_owned()),
+                (1, 4, "remote".to_owned()),
+                (2, 3, "pushed".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_finish_time() {
+        let (tracer, mut reporter) = new_tracer_with_deterministic_console_reporter(
+            IdGenerator::seeded(42),
+            SteppedClock::default(),
+        );
+        let later = SystemTime::UNIX_EPOCH + Duration::from_secs(60);
+        {
+            let mut root = tracer.span("root");
+            root.set_finish_time(|| later);
+            // Not from a deterministic source, so left alone
+            let _other = DeterministicSpan::from(crate::noop("other"));
+        }
+        reporter.drain();
+        let finish = reporter.with_spans(|mut spans| spans.next().unwrap().finish_time());
+        assert_eq!(finish, later);
+    }
+
+    #[test]
+    fn test_max_traces() {
+        let (span_tx, _) = crossbeam_channel::unbounded();
+        let tracer = DeterministicTracer::new(
+            Tracer::with_sender(crate::AllSampler, span_tx),
+            IdGenerator::seeded(43),
+            SteppedClock::default(),
+        );
+        let trace_id = |span: &DeterministicSpan| span.context().unwrap().0.state().trace_id();
+        let first = trace_id(&tracer.span("first"));
+        let mut last = first;
+        for _ in 0..MAX_TRACES {
+            last = trace_id(&tracer.span("root"));
+        }
+        let traces = lock(&TRACES);
+        assert!(!traces.contains_key(&first));
+        assert!(traces.contains_key(&last));
+        drop(traces);
+        drop(tracer);
+        assert!(!lock(&TRACES).contains_key(&last));
+    }
+
+    #[test]
+    fn test_id_generators() {
+        let mut sequential = IdGenerator::Sequential(0);
+        assert_eq!((sequential.next_id(), sequential.next_id()), (1, 2));
+        let mut a = IdGenerator::seeded(7);
+        let mut b = IdGenerator::seeded(7);
+        let a: Vec<_> = (0..4).map(|_| a.next_id()).collect();
+        assert_eq!(a, (0..4).map(|_| b.next_id()).collect::<Vec<_>>());
+        assert_ne!(a[0], a[1]);
+    }
+}
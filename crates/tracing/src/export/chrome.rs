@@ -0,0 +1,259 @@
+//! Export to the Chrome Trace Event Format, which can be loaded into
+//! chrome://tracing or https://ui.perfetto.dev.
+//! Spans become complete events, logs become instant events and `FollowsFrom`
+//! references become flow arrows. Processes and threads are taken from tags.
+
+use super::{duration_micros, inherited_tag, span_id, tag_json, SpanIndex};
+use crate::{conventions, FinishedSpan};
+use rustracing::span::SpanReference;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Which tags name the process and thread of a span. Spans without the tag
+/// take it from the span they reference, so tagging the root of each actor's
+/// spans is enough. Spans with no thread at all get one lane per trace.
+#[derive(Clone, Debug)]
+pub struct ChromeOptions {
+    pub process_tag: String,
+    pub thread_tag: String,
+}
+
+impl Default for ChromeOptions {
+    fn default() -> Self {
+        ChromeOptions {
+            process_tag: conventions::COMPONENT.into(),
+            thread_tag: conventions::THREAD_NAME.into(),
+        }
+    }
+}
+
+/// Write spans as a Chrome trace JSON document
+pub fn write<'a, I, W>(spans: I, options: &ChromeOptions, writer: &mut W) -> io::Result<()>
+where
+    I: IntoIterator<Item = &'a FinishedSpan>,
+    W: Write,
+{
+    serde_json::to_writer(writer, &to_json(spans, options)).map_err(io::Error::from)
+}
+
+/// Build a Chrome trace JSON document from spans
+pub fn to_json<'a, I>(spans: I, options: &ChromeOptions) -> Value
+where
+    I: IntoIterator<Item = &'a FinishedSpan>,
+{
+    let index = SpanIndex::new(spans);
+    let mut lanes = Lanes::default();
+    let mut events = Vec::new();
+    let mut flow_id = 0;
+    for span in index.spans.iter() {
+        let (pid, tid) = lanes.of(&index, span, options);
+        let mut args = Map::new();
+        for tag in span.tags() {
+            args.insert(tag.name().into(), tag_json(tag.value()));
+        }
+        args.insert("span_id".into(), json!(format!("{:x}", span_id(span))));
+        args.insert(
+            "trace_id".into(),
+            json!(span.context().state().trace_id().to_string()),
+        );
+        events.push(json!({
+            "name": span.operation_name(),
+            "cat": "span",
+            "ph": "X",
+            "ts": index.micros(span.start_time()),
+            "dur": duration_micros(span),
+            "pid": pid,
+            "tid": tid,
+            "args": args,
+        }));
+        for log in span.logs() {
+            let fields: Map<String, Value> = log
+                .fields()
+                .iter()
+                .map(|f| (f.name().to_string(), json!(f.value())))
+                .collect();
+            let name = log
+                .fields()
+                .iter()
+                .find(|f| f.name() == "event" || f.name() == "message")
+                .or_else(|| log.fields().first())
+                .map(|f| f.value())
+                .unwrap_or("log");
+            events.push(json!({
+                "name": name,
+                "cat": "log",
+                "ph": "i",
+                "s": "t",
+                "ts": index.micros(log.time()),
+                "pid": pid,
+                "tid": tid,
+                "args": fields,
+            }));
+        }
+        for reference in span.references() {
+            let source = match reference {
+                SpanReference::FollowsFrom(source) => index.get(source.span_id()),
+                SpanReference::ChildOf(_) => None,
+            };
+            if let Some(source) = source {
+                let (source_pid, source_tid) = lanes.of(&index, source, options);
+                // Start the arrow where the source was when the follower started
+                let from = span
+                    .start_time()
+                    .max(source.start_time())
+                    .min(source.finish_time());
+                flow_id += 1;
+                events.push(json!({
+                    "name": "follows_from",
+                    "cat": "follows_from",
+                    "ph": "s",
+                    "id": flow_id,
+                    "ts": index.micros(from),
+                    "pid": source_pid,
+                    "tid": source_tid,
+                }));
+                events.push(json!({
+                    "name": "follows_from",
+                    "cat": "follows_from",
+                    "ph": "f",
+                    "bp": "e",
+                    "id": flow_id,
+                    "ts": index.micros(span.start_time()),
+                    "pid": pid,
+                    "tid": tid,
+                }));
+            }
+        }
+    }
+    let mut metadata = lanes.metadata();
+    metadata.append(&mut events);
+    json!({
+        "traceEvents": metadata,
+        "displayTimeUnit": "ms",
+    })
+}
+
+/// Assigns numeric process and thread ids to the names found in tags
+#[derive(Default)]
+struct Lanes {
+    processes: Vec<String>,
+    threads: Vec<(usize, String)>,
+    resolved: HashMap<u64, (usize, usize)>,
+}
+
+impl Lanes {
+    fn of(
+        &mut self,
+        index: &SpanIndex,
+        span: &FinishedSpan,
+        options: &ChromeOptions,
+    ) -> (usize, usize) {
+        if let Some(lane) = self.resolved.get(&span_id(span)) {
+            return *lane;
+        }
+        let process = inherited_tag(index, span, &options.process_tag, true)
+            .unwrap_or_else(|| "unknown".into());
+        let thread = inherited_tag(index, span, &options.thread_tag, false)
+            .unwrap_or_else(|| format!("trace {}", span.context().state().trace_id()));
+        let pid = intern(&mut self.processes, process);
+        let tid = intern(&mut self.threads, (pid, thread));
+        self.resolved.insert(span_id(span), (pid, tid));
+        (pid, tid)
+    }
+
+    fn metadata(&self) -> Vec<Value> {
+        let processes = self.processes.iter().enumerate().map(|(i, name)| {
+            json!({
+                "name": "process_name",
+                "ph": "M",
+                "pid": i + 1,
+                "args": {"name": name},
+            })
+        });
+        let threads = self.threads.iter().enumerate().map(|(i, (pid, name))| {
+            json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": pid,
+                "tid": i + 1,
+                "args": {"name": name},
+            })
+        });
+        processes.chain(threads).collect()
+    }
+}
+
+/// 1-based position of `item` in `items`, appending it if needed
+fn intern<T: PartialEq>(items: &mut Vec<T>, item: T) -> usize {
+    match items.iter().position(|i| *i == item) {
+        Some(i) => i + 1,
+        None => {
+            items.push(item);
+            items.len()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer_console::new_tracer_with_console_reporter;
+    use crate::Tag;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_chrome_export() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        let t0 = SystemTime::now();
+        let ms = Duration::from_millis;
+        {
+            let mut parent = tracer
+                .span("parent")
+                .start_time(t0)
+                .tag(Tag::new("component", "alice"))
+                .tag(Tag::new("thread.name", "main"))
+                .start();
+            parent.set_finish_time(|| t0 + ms(10));
+            let mut child = parent.child("child", |o| o.start_time(t0 + ms(1)).start());
+            child.set_finish_time(|| t0 + ms(3));
+            child.log(|l| {
+                l.time(t0 + ms(2)).std().event("hello");
+            });
+            let mut follower = parent.follower("follower", |o| {
+                o.start_time(t0 + ms(5))
+                    .tag(Tag::new("component", "bob"))
+                    .start()
+            });
+            follower.set_finish_time(|| t0 + ms(20));
+        }
+        reporter.drain();
+        let doc = reporter.with_spans(|spans| to_json(spans, &ChromeOptions::default()));
+        let events = doc["traceEvents"].as_array().unwrap();
+        let of_phase =
+            |ph: &str| -> Vec<&Value> { events.iter().filter(|e| e["ph"] == ph).collect() };
+        let names: Vec<_> = of_phase("M")
+            .iter()
+            .map(|e| e["args"]["name"].clone())
+            .collect();
+        assert_eq!(names.len(), 4);
+        assert!(names.contains(&json!("alice")) && names.contains(&json!("bob")));
+        assert!(names.contains(&json!("main")));
+        let spans = of_phase("X");
+        assert_eq!(spans.len(), 3);
+        assert_eq!(spans[1]["name"], "child");
+        assert_eq!(spans[1]["ts"], 1000);
+        assert_eq!(spans[1]["dur"], 2000);
+        // The child inherits the parent's lane, the follower gets its own
+        assert_eq!(spans[1]["tid"], spans[0]["tid"]);
+        assert_ne!(spans[2]["pid"], spans[0]["pid"]);
+        let logs = of_phase("i");
+        assert_eq!(logs[0]["name"], "hello");
+        assert_eq!(logs[0]["ts"], 2000);
+        let flow_start = of_phase("s");
+        let flow_end = of_phase("f");
+        assert_eq!(flow_start[0]["ts"], 5000);
+        assert_eq!(flow_start[0]["pid"], spans[0]["pid"]);
+        assert_eq!(flow_end[0]["pid"], spans[2]["pid"]);
+    }
+}
@@ -0,0 +1,118 @@
+//! Export to folded stacks, the input format of flamegraph tools such as
+//! `flamegraph.pl` and `inferno-flamegraph`. Each line is a `;` separated
+//! stack of operation names, following `ChildOf` references up to the root,
+//! and the total self time of that stack in microseconds.
+//! Followers start stacks of their own, since they don't run inside the
+//! span they follow from.
+
+use super::{duration_micros, span_id, SpanIndex};
+use crate::FinishedSpan;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+/// Write spans as folded stack lines
+pub fn write<'a, I, W>(spans: I, writer: &mut W) -> io::Result<()>
+where
+    I: IntoIterator<Item = &'a FinishedSpan>,
+    W: Write,
+{
+    for (stack, micros) in fold(spans) {
+        writeln!(writer, "{} {}", stack, micros)?;
+    }
+    Ok(())
+}
+
+/// Folded stacks with their self time in microseconds, sorted by stack.
+/// Stacks with no self time are left out.
+pub fn fold<'a, I>(spans: I) -> BTreeMap<String, u64>
+where
+    I: IntoIterator<Item = &'a FinishedSpan>,
+{
+    let index = SpanIndex::new(spans);
+    let mut child_time: HashMap<u64, u64> = HashMap::new();
+    for span in index.spans.iter() {
+        if let Some(parent) = parent(&index, span) {
+            *child_time.entry(span_id(parent)).or_default() += duration_micros(span);
+        }
+    }
+    let mut stacks = BTreeMap::new();
+    for span in index.spans.iter() {
+        // Children running in parallel can add up to more than their parent
+        let self_time = duration_micros(span)
+            .saturating_sub(child_time.get(&span_id(span)).copied().unwrap_or(0));
+        if self_time > 0 {
+            *stacks.entry(stack(&index, span)).or_default() += self_time;
+        }
+    }
+    stacks
+}
+
+/// The parent of a span, if it has one among the collected spans
+fn parent<'a>(index: &SpanIndex<'a>, span: &FinishedSpan) -> Option<&'a FinishedSpan> {
+    span.references()
+        .iter()
+        .filter(|r| r.is_child_of())
+        .find_map(|r| index.get(r.span().span_id()))
+}
+
+fn stack(index: &SpanIndex, span: &FinishedSpan) -> String {
+    let mut frames = vec![frame(span.operation_name())];
+    let mut current = span;
+    // Bounded by the number of spans, in case the references form a cycle
+    for _ in 0..index.spans.len() {
+        match parent(index, current) {
+            Some(p) => {
+                frames.push(frame(p.operation_name()));
+                current = p;
+            }
+            None => break,
+        }
+    }
+    frames.reverse();
+    frames.join(";")
+}
+
+/// Frame names can't contain the separator, or line breaks
+fn frame(operation_name: &str) -> String {
+    operation_name
+        .chars()
+        .map(|c| match c {
+            ';' => ',',
+            '\n' | '\r' => ' ',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer_console::new_tracer_with_console_reporter;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_fold() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        let t0 = SystemTime::now();
+        let ms = Duration::from_millis;
+        {
+            let mut root = tracer.span("root").start_time(t0).start();
+            root.set_finish_time(|| t0 + ms(10));
+            for i in 0..2 {
+                let mut a = root.child("a", |o| o.start_time(t0 + ms(i * 4)).start());
+                a.set_finish_time(|| t0 + ms(i * 4 + 3));
+                let mut b = a.child("b;c", |o| o.start_time(t0 + ms(i * 4)).start());
+                b.set_finish_time(|| t0 + ms(i * 4 + 1));
+            }
+            let mut follower = root.follower("later", |o| o.start_time(t0 + ms(10)).start());
+            follower.set_finish_time(|| t0 + ms(15));
+        }
+        reporter.drain();
+        let mut out = Vec::new();
+        reporter.with_spans(|spans| write(spans, &mut out)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "later 5000\nroot 4000\nroot;a 4000\nroot;a;b,c 2000\n"
+        );
+    }
+}
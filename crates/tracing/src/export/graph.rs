@@ -0,0 +1,220 @@
+//! Export of the relationships between spans as diagrams: a Graphviz DOT graph
+//! of all spans, and a Mermaid sequence diagram of the messages passed between
+//! actors. Child-of and follows-from relationships are drawn in different styles,
+//! so that followers don't look like siblings the way they do in the console tree.
+
+use super::{duration_micros, inherited_tag, span_id, SpanIndex};
+use crate::{conventions, FinishedSpan};
+use rustracing::span::SpanReference;
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+
+/// Which tag names the actor, e.g. service or thread, that a span ran in.
+/// Spans without the tag take it from the span they reference.
+#[derive(Clone, Debug)]
+pub struct GraphOptions {
+    pub actor_tag: String,
+}
+
+impl Default for GraphOptions {
+    fn default() -> Self {
+        GraphOptions {
+            actor_tag: conventions::COMPONENT.into(),
+        }
+    }
+}
+
+const UNKNOWN_ACTOR: &str = "unknown";
+
+/// Write spans as a Graphviz DOT digraph
+pub fn write_dot<'a, I, W>(spans: I, options: &GraphOptions, writer: &mut W) -> io::Result<()>
+where
+    I: IntoIterator<Item = &'a FinishedSpan>,
+    W: Write,
+{
+    writer.write_all(to_dot(spans, options).as_bytes())
+}
+
+/// Write spans as a Mermaid sequence diagram
+pub fn write_mermaid<'a, I, W>(spans: I, options: &GraphOptions, writer: &mut W) -> io::Result<()>
+where
+    I: IntoIterator<Item = &'a FinishedSpan>,
+    W: Write,
+{
+    writer.write_all(to_mermaid(spans, options).as_bytes())
+}
+
+/// A Graphviz DOT digraph with one node per span, clustered by actor.
+/// Child-of edges are solid, follows-from edges are dashed.
+pub fn to_dot<'a, I>(spans: I, options: &GraphOptions) -> String
+where
+    I: IntoIterator<Item = &'a FinishedSpan>,
+{
+    let index = SpanIndex::new(spans);
+    let mut clusters: BTreeMap<String, Vec<&FinishedSpan>> = BTreeMap::new();
+    for span in index.spans.iter() {
+        clusters
+            .entry(actor(&index, span, options))
+            .or_default()
+            .push(span);
+    }
+    let mut out = String::new();
+    out.push_str("digraph spans {\n  rankdir=LR;\n  node [shape=box];\n");
+    for (i, (actor, spans)) in clusters.iter().enumerate() {
+        let _ = writeln!(out, "  subgraph cluster_{} {{", i);
+        let _ = writeln!(out, "    label=\"{}\";", escape_dot(actor));
+        for span in spans {
+            let _ = writeln!(
+                out,
+                "    \"{:x}\" [label=\"{}\\n{}us\"];",
+                span_id(span),
+                escape_dot(span.operation_name()),
+                duration_micros(span)
+            );
+        }
+        out.push_str("  }\n");
+    }
+    for span in index.spans.iter() {
+        for reference in span.references() {
+            // Edges to spans we never received would only add empty nodes
+            if index.get(reference.span().span_id()).is_none() {
+                continue;
+            }
+            let style = match reference {
+                SpanReference::ChildOf(_) => "solid",
+                SpanReference::FollowsFrom(_) => "dashed",
+            };
+            let _ = writeln!(
+                out,
+                "  \"{:x}\" -> \"{:x}\" [style={}];",
+                reference.span().span_id(),
+                span_id(span),
+                style
+            );
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// A Mermaid sequence diagram with one participant per actor, and one message
+/// per span started by a span in another actor. Child-of messages are solid
+/// arrows, follows-from messages are dotted async arrows.
+pub fn to_mermaid<'a, I>(spans: I, options: &GraphOptions) -> String
+where
+    I: IntoIterator<Item = &'a FinishedSpan>,
+{
+    let index = SpanIndex::new(spans);
+    let mut actors: Vec<String> = Vec::new();
+    let mut messages = String::new();
+    let mut participant = |name: String| match actors.iter().position(|a| *a == name) {
+        Some(i) => i + 1,
+        None => {
+            actors.push(name);
+            actors.len()
+        }
+    };
+    for span in index.spans.iter() {
+        let to = actor(&index, span, options);
+        let to_id = participant(to.clone());
+        for reference in span.references() {
+            let from_span = match index.get(reference.span().span_id()) {
+                Some(from_span) => from_span,
+                None => continue,
+            };
+            let from = actor(&index, from_span, options);
+            if from == to {
+                continue;
+            }
+            let from_id = participant(from);
+            let arrow = match reference {
+                SpanReference::ChildOf(_) => "->>",
+                SpanReference::FollowsFrom(_) => "--)",
+            };
+            let _ = writeln!(
+                messages,
+                "    a{}{}a{}: {}",
+                from_id,
+                arrow,
+                to_id,
+                escape_mermaid(span.operation_name())
+            );
+        }
+    }
+    let mut out = String::from("sequenceDiagram\n");
+    for (i, name) in actors.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "    participant a{} as {}",
+            i + 1,
+            escape_mermaid(name)
+        );
+    }
+    out.push_str(&messages);
+    out
+}
+
+fn actor(index: &SpanIndex, span: &FinishedSpan, options: &GraphOptions) -> String {
+    inherited_tag(index, span, &options.actor_tag, true).unwrap_or_else(|| UNKNOWN_ACTOR.into())
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Mermaid reads `;` and `#` as syntax, and has no escaping for line breaks
+fn escape_mermaid(s: &str) -> String {
+    s.replace(';', ",").replace('#', "no. ").replace('\n', " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer_console::new_tracer_with_console_reporter;
+    use crate::Tag;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_graphs() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        let t0 = SystemTime::now();
+        let ms = Duration::from_millis;
+        let (root_id, child_id, follower_id) = {
+            let root = tracer
+                .span("send")
+                .start_time(t0)
+                .tag(Tag::new("component", "alice"))
+                .start();
+            let child = root.child("prepare", |o| o.start_time(t0 + ms(1)).start());
+            let follower = root.follower("receive \"msg\"", |o| {
+                o.start_time(t0 + ms(2))
+                    .tag(Tag::new("component", "bob"))
+                    .start()
+            });
+            let id = |s: &rustracing_jaeger::Span| s.context().unwrap().state().span_id();
+            (id(&root), id(&child), id(&follower))
+        };
+        reporter.drain();
+        let options = GraphOptions::default();
+        let dot = reporter.with_spans(|spans| to_dot(spans, &options));
+        assert!(dot.contains("label=\"alice\";"));
+        assert!(dot.contains("label=\"bob\";"));
+        assert!(dot.contains("label=\"receive \\\"msg\\\"\\n"));
+        assert!(dot.contains(&format!(
+            "\"{:x}\" -> \"{:x}\" [style=solid];",
+            root_id, child_id
+        )));
+        assert!(dot.contains(&format!(
+            "\"{:x}\" -> \"{:x}\" [style=dashed];",
+            root_id, follower_id
+        )));
+        let mermaid = reporter.with_spans(|spans| to_mermaid(spans, &options));
+        assert_eq!(
+            mermaid,
+            "sequenceDiagram\n    participant a1 as alice\n    participant a2 as bob\n    a1--)a2: receive \"msg\"\n"
+        );
+    }
+}
@@ -0,0 +1,403 @@
+//! Export to the JSON format served by the Jaeger query API, which the Jaeger UI
+//! can also load from a file ("JSON File" on the search page). This lets traces
+//! from CI runs be shared and viewed without a Jaeger agent. `read` turns such
+//! a document back into spans.
+
+use super::{duration_micros, inherited_tag, micros, span_id, SpanIndex};
+use crate::rebuild::{array, context_state, field_str, tag_value, Rebuilder, SpanParts};
+use crate::tracer_file::RecordedSpan;
+use crate::{conventions, FinishedSpan};
+use rustracing::log::LogField;
+use rustracing::span::SpanReference;
+use rustracing::tag::{Tag, TagValue};
+use rustracing_jaeger::span::TraceId;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::time::{Duration, SystemTime};
+
+/// Which tag names the service of a span. Spans without the tag take it from
+/// the span they reference, and fall back to `default_service`.
+#[derive(Clone, Debug)]
+pub struct JaegerOptions {
+    pub service_tag: String,
+    pub default_service: String,
+}
+
+impl Default for JaegerOptions {
+    fn default() -> Self {
+        JaegerOptions {
+            service_tag: conventions::COMPONENT.into(),
+            default_service: "holochain".into(),
+        }
+    }
+}
+
+/// Write spans as a Jaeger JSON document
+pub fn write<'a, I, W>(spans: I, options: &JaegerOptions, writer: &mut W) -> io::Result<()>
+where
+    I: IntoIterator<Item = &'a FinishedSpan>,
+    W: Write,
+{
+    serde_json::to_writer(writer, &to_json(spans, options)).map_err(io::Error::from)
+}
+
+/// Build a Jaeger JSON document from spans, with one entry per trace id
+pub fn to_json<'a, I>(spans: I, options: &JaegerOptions) -> Value
+where
+    I: IntoIterator<Item = &'a FinishedSpan>,
+{
+    let index = SpanIndex::new(spans);
+    let mut traces: BTreeMap<TraceId, Vec<&FinishedSpan>> = BTreeMap::new();
+    for span in index.spans.iter() {
+        traces
+            .entry(span.context().state().trace_id())
+            .or_default()
+            .push(span);
+    }
+    let data: Vec<Value> = traces
+        .iter()
+        .map(|(trace_id, spans)| {
+            // Process ids are only unique within a trace
+            let mut services: Vec<String> = Vec::new();
+            let spans: Vec<Value> = spans
+                .iter()
+                .map(|span| {
+                    let service = inherited_tag(&index, span, &options.service_tag, true)
+                        .unwrap_or_else(|| options.default_service.clone());
+                    let process = match services.iter().position(|s| *s == service) {
+                        Some(i) => i + 1,
+                        None => {
+                            services.push(service);
+                            services.len()
+                        }
+                    };
+                    span_json(span, process)
+                })
+                .collect();
+            let processes: serde_json::Map<String, Value> = services
+                .into_iter()
+                .enumerate()
+                .map(|(i, service)| {
+                    (
+                        format!("p{}", i + 1),
+                        json!({"serviceName": service, "tags": []}),
+                    )
+                })
+                .collect();
+            json!({
+                "traceID": format_trace_id(*trace_id),
+                "spans": spans,
+                "processes": processes,
+                "warnings": null,
+            })
+        })
+        .collect();
+    json!({
+        "data": data,
+        "total": 0,
+        "limit": 0,
+        "offset": 0,
+        "errors": null,
+    })
+}
+
+fn span_json(span: &FinishedSpan, process: usize) -> Value {
+    let state = span.context().state();
+    let references: Vec<Value> = span
+        .references()
+        .iter()
+        .map(|reference| {
+            let ref_type = match reference {
+                SpanReference::ChildOf(_) => "CHILD_OF",
+                SpanReference::FollowsFrom(_) => "FOLLOWS_FROM",
+            };
+            json!({
+                "refType": ref_type,
+                "traceID": format_trace_id(reference.span().trace_id()),
+                "spanID": format_span_id(reference.span().span_id()),
+            })
+        })
+        .collect();
+    let tags: Vec<Value> = span
+        .tags()
+        .iter()
+        .map(|tag| key_value(tag.name(), tag.value()))
+        .collect();
+    let logs: Vec<Value> = span
+        .logs()
+        .iter()
+        .map(|log| {
+            let fields: Vec<Value> = log
+                .fields()
+                .iter()
+                .map(|f| json!({"key": f.name(), "type": "string", "value": f.value()}))
+                .collect();
+            json!({"timestamp": epoch_micros(log.time()), "fields": fields})
+        })
+        .collect();
+    json!({
+        "traceID": format_trace_id(state.trace_id()),
+        "spanID": format_span_id(span_id(span)),
+        "flags": 1,
+        "operationName": span.operation_name(),
+        "references": references,
+        "startTime": epoch_micros(span.start_time()),
+        "duration": duration_micros(span),
+        "tags": tags,
+        "logs": logs,
+        "processID": format!("p{}", process),
+        "warnings": null,
+    })
+}
+
+fn key_value(key: &str, value: &TagValue) -> Value {
+    let (value_type, value) = match value {
+        TagValue::String(s) => ("string", json!(s)),
+        TagValue::Boolean(b) => ("bool", json!(b)),
+        TagValue::Integer(i) => ("int64", json!(i)),
+        TagValue::Float(f) if f.is_finite() => ("float64", json!(f)),
+        // JSON has no NaN or infinity
+        TagValue::Float(f) => ("float64", json!(f.to_string())),
+    };
+    json!({"key": key, "type": value_type, "value": value})
+}
+
+/// Jaeger ids are zero padded lowercase hex
+fn format_trace_id(trace_id: TraceId) -> String {
+    if trace_id.high == 0 {
+        format!("{:016x}", trace_id.low)
+    } else {
+        format!("{:016x}{:016x}", trace_id.high, trace_id.low)
+    }
+}
+
+fn format_span_id(span_id: u64) -> String {
+    format!("{:016x}", span_id)
+}
+
+fn epoch_micros(time: SystemTime) -> u64 {
+    micros(
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default(),
+    )
+}
+
+/// Read the spans of a Jaeger JSON document, each with the service name of
+/// its process
+pub fn read<R: Read>(reader: R) -> io::Result<Vec<RecordedSpan>> {
+    let doc: Value = serde_json::from_reader(reader).map_err(io::Error::from)?;
+    from_json(&doc)
+}
+
+/// The spans of a parsed Jaeger JSON document. Anything but a document with a
+/// `data` array of traces is an `InvalidData` error.
+pub fn from_json(doc: &Value) -> io::Result<Vec<RecordedSpan>> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    if !doc["data"].is_array() {
+        return Err(invalid("not a Jaeger JSON document".into()));
+    }
+    let rebuilder = Rebuilder::new();
+    let mut spans = Vec::new();
+    for trace in array(doc, "data") {
+        for span in array(trace, "spans") {
+            let service = span["processID"]
+                .as_str()
+                .and_then(|process| trace["processes"][process]["serviceName"].as_str())
+                .unwrap_or_default()
+                .to_owned();
+            let span =
+                span_parts(span).map_err(|e| invalid(format!("span {}: {}", span["spanID"], e)))?;
+            spans.push(RecordedSpan {
+                service,
+                span: rebuilder.build(span),
+            });
+        }
+    }
+    Ok(spans)
+}
+
+fn span_parts(span: &Value) -> Result<SpanParts, String> {
+    let references = array(span, "references")
+        .iter()
+        .map(|reference| {
+            let child_of = match reference["refType"].as_str() {
+                Some("CHILD_OF") => true,
+                Some("FOLLOWS_FROM") => false,
+                _ => return Err(format!("unknown refType {}", reference["refType"])),
+            };
+            Ok((child_of, context_state(reference, "traceID", "spanID")?))
+        })
+        .collect::<Result<_, String>>()?;
+    let tags = array(span, "tags")
+        .iter()
+        .map(|tag| {
+            let value = match (tag["type"].as_str(), &tag["value"]) {
+                (Some("float64"), Value::Number(n)) => {
+                    TagValue::from(n.as_f64().unwrap_or_default())
+                }
+                // Non-finite floats are written as strings
+                (Some("float64"), Value::String(s)) => TagValue::from(
+                    s.parse::<f64>()
+                        .map_err(|_| format!("{:?} is not a float", s))?,
+                ),
+                _ => tag_value(&tag["value"])?,
+            };
+            Ok(Tag::new(field_str(tag, "key")?.to_owned(), value))
+        })
+        .collect::<Result<_, String>>()?;
+    let logs = array(span, "logs")
+        .iter()
+        .map(|log| {
+            let fields = array(log, "fields")
+                .iter()
+                .map(|field| {
+                    let value = match &field["value"] {
+                        Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    };
+                    Ok(LogField::new(field_str(field, "key")?.to_owned(), value))
+                })
+                .collect::<Result<_, String>>()?;
+            Ok((epoch_time(log, "timestamp")?, fields))
+        })
+        .collect::<Result<_, String>>()?;
+    let start = epoch_time(span, "startTime")?;
+    let duration = span["duration"]
+        .as_u64()
+        .ok_or_else(|| "missing duration".to_string())?;
+    Ok(SpanParts {
+        state: context_state(span, "traceID", "spanID")?,
+        operation: field_str(span, "operationName")?.to_owned(),
+        start,
+        finish: start + Duration::from_micros(duration),
+        references,
+        tags,
+        logs,
+    })
+}
+
+fn epoch_time(value: &Value, key: &str) -> Result<SystemTime, String> {
+    value[key]
+        .as_u64()
+        .map(|micros| SystemTime::UNIX_EPOCH + Duration::from_micros(micros))
+        .ok_or_else(|| format!("missing {}", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer_console::new_tracer_with_console_reporter;
+    use crate::Tag;
+
+    #[test]
+    fn test_jaeger_export() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        {
+            let parent = tracer
+                .span("parent")
+                .tag(Tag::new("component", "alice"))
+                .tag(Tag::new("attempt", 2))
+                .start();
+            let mut child = parent.child("child", |o| o.start());
+            child.set_tag(|| Tag::new("ratio", "inf".parse::<f64>().unwrap()));
+            child.log(|l| {
+                l.std().event("hello");
+            });
+            let _follower = parent.follower("follower", |o| o.start());
+            let _other_trace = tracer.span("other").start();
+        }
+        reporter.drain();
+        let doc = reporter.with_spans(|spans| to_json(spans, &JaegerOptions::default()));
+        let traces = doc["data"].as_array().unwrap();
+        assert_eq!(traces.len(), 2);
+        let trace = traces
+            .iter()
+            .find(|t| t["spans"].as_array().unwrap().len() == 3)
+            .unwrap();
+        assert_eq!(trace["processes"]["p1"]["serviceName"], "alice");
+        let spans = trace["spans"].as_array().unwrap();
+        let by_name = |name: &str| spans.iter().find(|s| s["operationName"] == name).unwrap();
+        let parent = by_name("parent");
+        assert_eq!(parent["spanID"].as_str().unwrap().len(), 16);
+        assert!(parent["tags"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({"key": "attempt", "type": "int64", "value": 2})));
+        let child = by_name("child");
+        assert_eq!(child["processID"], "p1");
+        assert_eq!(child["references"][0]["refType"], "CHILD_OF");
+        assert_eq!(child["references"][0]["spanID"], parent["spanID"]);
+        assert_eq!(child["logs"][0]["fields"][0]["value"], "hello");
+        assert!(child["tags"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({"key": "ratio", "type": "float64", "value": "inf"})));
+        assert_eq!(
+            by_name("follower")["references"][0]["refType"],
+            "FOLLOWS_FROM"
+        );
+        let other = traces.iter().find(|t| t != &trace).unwrap();
+        assert_eq!(other["processes"]["p1"]["serviceName"], "holochain");
+    }
+
+    #[test]
+    fn test_jaeger_read() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        {
+            let parent = tracer
+                .span("parent")
+                .tag(Tag::new("component", "alice"))
+                .start();
+            let mut child = parent.child("child", |o| o.start());
+            child.set_tag(|| Tag::new("ratio", 1.0));
+            child.set_tag(|| Tag::new("attempt", 2));
+            child.set_tag(|| Tag::new("nan", "NaN".parse::<f64>().unwrap()));
+            child.set_tag(|| Tag::new("inf", "-inf".parse::<f64>().unwrap()));
+            child.log(|l| {
+                l.std().event("hello");
+            });
+            let _follower = parent.follower("follower", |o| o.start());
+        }
+        reporter.drain();
+        let mut doc = Vec::new();
+        reporter
+            .with_spans(|spans| write(spans, &JaegerOptions::default(), &mut doc))
+            .unwrap();
+        let spans = read(&doc[..]).unwrap();
+        assert_eq!(spans.len(), 3);
+        assert!(spans.iter().all(|s| s.service == "alice"));
+        let child = spans
+            .iter()
+            .find(|s| s.span.operation_name() == "child")
+            .unwrap();
+        let tag = |name: &str| {
+            child
+                .span
+                .tags()
+                .iter()
+                .find(|t| t.name() == name)
+                .map(|t| t.value().clone())
+        };
+        match tag("nan") {
+            Some(TagValue::Float(f)) => assert!(f.is_nan()),
+            other => panic!("NaN read back as {:?}", other),
+        }
+        assert_eq!(
+            tag("inf"),
+            Some(TagValue::from("-inf".parse::<f64>().unwrap()))
+        );
+        let (span_tx, span_rx) = crossbeam_channel::unbounded();
+        for span in spans {
+            span_tx.send(span.span).unwrap();
+        }
+        let mut read_back = crate::tracer_console::ConsoleReporter::new(span_rx);
+        read_back.drain();
+        assert_eq!(
+            read_back.render_to_string(false),
+            reporter.render_to_string(false)
+        );
+        let error = read(&b"{\"spans\": []}"[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
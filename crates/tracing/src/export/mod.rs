@@ -0,0 +1,114 @@
+//! Exporters turning finished spans into formats which other tools can load.
+//! Every exporter takes any iterator of `FinishedSpan`s, so they work on the
+//! spans collected by a `ConsoleReporter` (see `ConsoleReporter::with_spans`)
+//! as well as on spans received straight from a tracer's channel.
+
+pub mod chrome;
+pub mod folded;
+pub mod graph;
+pub mod jaeger;
+
+use crate::FinishedSpan;
+use rustracing::tag::{Tag, TagValue};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/// Collected spans indexed by span id, with the earliest start time as the
+/// zero point for relative timestamps
+struct SpanIndex<'a> {
+    spans: Vec<&'a FinishedSpan>,
+    by_id: HashMap<u64, &'a FinishedSpan>,
+    epoch: SystemTime,
+}
+
+impl<'a> SpanIndex<'a> {
+    fn new<I: IntoIterator<Item = &'a FinishedSpan>>(spans: I) -> Self {
+        let mut spans: Vec<_> = spans.into_iter().collect();
+        // Sorting makes the output independent of the order the spans came in
+        spans.sort_by_key(|s| (s.start_time(), span_id(s)));
+        let by_id = spans.iter().map(|s| (span_id(s), *s)).collect();
+        let epoch = spans
+            .iter()
+            .map(|s| s.start_time())
+            .min()
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        SpanIndex {
+            spans,
+            by_id,
+            epoch,
+        }
+    }
+
+    fn get(&self, span_id: u64) -> Option<&'a FinishedSpan> {
+        self.by_id.get(&span_id).copied()
+    }
+
+    /// Microseconds since the earliest span started
+    fn micros(&self, time: SystemTime) -> u64 {
+        micros(time.duration_since(self.epoch).unwrap_or_default())
+    }
+}
+
+fn span_id(span: &FinishedSpan) -> u64 {
+    span.context().state().span_id()
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())
+}
+
+fn duration_micros(span: &FinishedSpan) -> u64 {
+    micros(
+        span.finish_time()
+            .duration_since(span.start_time())
+            .unwrap_or_default(),
+    )
+}
+
+fn tag_json(value: &TagValue) -> Value {
+    match value {
+        TagValue::String(s) => Value::from(s.as_ref()),
+        TagValue::Boolean(b) => Value::from(*b),
+        TagValue::Integer(i) => Value::from(*i),
+        TagValue::Float(f) => Value::from(*f),
+    }
+}
+
+pub(crate) fn tag_string(tag: &Tag) -> String {
+    match tag.value() {
+        TagValue::String(s) => s.to_string(),
+        TagValue::Boolean(b) => b.to_string(),
+        TagValue::Integer(i) => i.to_string(),
+        TagValue::Float(f) => f.to_string(),
+    }
+}
+
+fn find_tag<'a>(span: &'a FinishedSpan, key: &str) -> Option<&'a Tag> {
+    span.tags().iter().find(|t| t.name() == key)
+}
+
+/// The value of `key` on this span or the nearest span it references, so that
+/// e.g. only the root span of each actor needs to be tagged with its name.
+/// With `through_followers` unset only parents are searched, which suits
+/// threads since followers usually run elsewhere.
+fn inherited_tag(
+    index: &SpanIndex,
+    span: &FinishedSpan,
+    key: &str,
+    through_followers: bool,
+) -> Option<String> {
+    let mut current = span;
+    // Bounded by the number of spans, in case the references form a cycle
+    for _ in 0..=index.spans.len() {
+        if let Some(tag) = find_tag(current, key) {
+            return Some(tag_string(tag));
+        }
+        current = current
+            .references()
+            .iter()
+            .filter(|r| through_followers || r.is_child_of())
+            .find_map(|r| index.get(r.span().span_id()))?;
+    }
+    None
+}
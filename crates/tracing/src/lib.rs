@@ -13,18 +13,31 @@ extern crate serde;
 extern crate serde_derive;
 
 pub mod channel;
+pub mod conventions;
+pub mod deterministic;
+pub mod export;
+pub mod metrics;
+mod pretty;
+mod rebuild;
 mod span;
 mod span_context;
 mod span_wrap;
 mod stack;
+pub mod stats;
 pub mod structured;
 mod tag;
+pub mod task;
+pub mod testing;
+pub mod thread_pool;
+mod trace_tree;
 pub mod tracer_console;
+pub mod tracer_file;
 pub mod tracer_network;
 
 #[macro_use]
 pub mod tracing_macros;
 pub mod prelude;
+pub mod query;
 #[cfg(feature = "experimental-jaeger")]
 pub mod tracing;
 mod utils;
@@ -34,6 +47,15 @@ pub use rustracing_jaeger::{reporter, span::FinishedSpan, Tracer};
 pub use span::{noop, null_tracer, test_span, HSpan as Span};
 pub use span_context::{EncodedSpanContext, HSpanContext as SpanContext};
 pub use span_wrap::{test_wrap, test_wrap_enc, EncodedSpanWrap, SpanWrap};
-pub use stack::{is_empty, push_span, push_span_with, top_follower, with_top, with_top_or_null};
-pub use tag::debug_tag;
-pub use utils::{follow, follow_encoded, follow_encoded_tag, wrap, wrap_with_tag};
+pub use stack::{
+    capture, is_empty, push_span, push_span_with, set_span_limits, span_limits, top_follower,
+    with_span_limits, with_top, with_top_or_null, SpanLimits, SpanSnapshot, TaskStack,
+};
+pub use tag::{
+    base64_tag, base64_tag_capped, debug_tag, debug_tag_capped, display_tag, display_tag_capped,
+    hex_tag, hex_tag_capped, json_tag, json_tag_capped, TRUNCATION_MARKER,
+};
+#[allow(deprecated)]
+pub use utils::{
+    follow, follow_encoded, follow_encoded_tag, wrap, wrap_with_tag, Relation, SpanBuilder,
+};
@@ -0,0 +1,377 @@
+//! RED metrics (rate, errors, duration) derived from finished spans, so that
+//! autotraced operations get metrics without any metric calls of their own.
+//! `Metrics` keeps a request counter, an error counter and a duration
+//! histogram per operation name and the values of selected tag keys, and
+//! renders them in the Prometheus text exposition format.
+//!
+//! Spans can't be cloned, so `Metrics` sits in front of other reporters:
+//! `forward` returns a sender for the tracer which observes each span and then
+//! passes it on, e.g. to a `ConsoleReporter`'s channel or to
+//! `tracer_network::network_reporter_sender`.
+
+use crate::export::tag_string;
+use crate::{conventions, FinishedSpan};
+use rustracing::tag::TagValue;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+/// The default Prometheus histogram buckets, in seconds
+pub const DEFAULT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters and duration histograms per operation and tag values.
+/// Clones share the same metrics.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    namespace: String,
+    tag_keys: Vec<String>,
+    buckets: Vec<f64>,
+    series: BTreeMap<SeriesKey, Series>,
+}
+
+/// Operation name, then the value of each tag key, empty if the span lacks it
+type SeriesKey = (String, Vec<String>);
+
+#[derive(Debug, Default)]
+struct Series {
+    count: u64,
+    errors: u64,
+    sum_seconds: f64,
+    /// Observations per bucket, not yet cumulative
+    buckets: Vec<u64>,
+}
+
+impl Metrics {
+    /// Metrics named `<namespace>_spans_total`, `<namespace>_span_errors_total`
+    /// and `<namespace>_span_duration_seconds`, labelled by operation only
+    pub fn new<S: Into<String>>(namespace: S) -> Self {
+        Metrics {
+            inner: Arc::new(Mutex::new(Inner {
+                namespace: namespace.into(),
+                tag_keys: Vec::new(),
+                buckets: DEFAULT_BUCKETS.to_vec(),
+                series: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Also label by the values of these tags, e.g. `conventions::ZOME_NAME`.
+    /// Dots in tag keys become underscores in label names.
+    pub fn with_tags<I, K>(self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        {
+            let mut inner = self.lock();
+            inner.tag_keys = keys.into_iter().map(Into::into).collect();
+            inner.series.clear();
+        }
+        self
+    }
+
+    /// Use these histogram bucket bounds, in seconds, instead of `DEFAULT_BUCKETS`
+    pub fn with_buckets(self, buckets: &[f64]) -> Self {
+        {
+            let mut inner = self.lock();
+            inner.buckets = buckets.to_vec();
+            inner
+                .buckets
+                .sort_by(|a, b| a.partial_cmp(b).expect("Bucket bounds are numbers"));
+            inner.series.clear();
+        }
+        self
+    }
+
+    /// Count a span
+    pub fn observe(&self, span: &FinishedSpan) {
+        let mut inner = self.lock();
+        let tags = inner
+            .tag_keys
+            .iter()
+            .map(|key| {
+                span.tags()
+                    .iter()
+                    .find(|t| t.name() == key)
+                    .map(tag_string)
+                    .unwrap_or_default()
+            })
+            .collect();
+        let seconds = span
+            .finish_time()
+            .duration_since(span.start_time())
+            .unwrap_or_default()
+            .as_secs_f64();
+        let failed = span
+            .tags()
+            .iter()
+            .any(|t| t.name() == conventions::ERROR && *t.value() == TagValue::Boolean(true));
+        let bucket = inner.buckets.iter().position(|le| seconds <= *le);
+        let bucket_count = inner.buckets.len();
+        let series = inner
+            .series
+            .entry((span.operation_name().to_string(), tags))
+            .or_default();
+        series.buckets.resize(bucket_count, 0);
+        series.count += 1;
+        series.sum_seconds += seconds;
+        if failed {
+            series.errors += 1;
+        }
+        if let Some(bucket) = bucket {
+            series.buckets[bucket] += 1;
+        }
+    }
+
+    /// A sender for a tracer which observes every span and then passes it on
+    /// to `downstream`, if any, from a background thread. The thread ends once
+    /// every clone of the returned sender is dropped.
+    pub fn forward(
+        &self,
+        downstream: Option<crossbeam_channel::Sender<FinishedSpan>>,
+    ) -> crossbeam_channel::Sender<FinishedSpan> {
+        let (span_tx, span_rx) = crossbeam_channel::bounded::<FinishedSpan>(1000);
+        let metrics = self.clone();
+        thread::Builder::new()
+            .name("span-metrics".into())
+            .spawn(move || {
+                for span in span_rx {
+                    metrics.observe(&span);
+                    if let Some(downstream) = downstream.as_ref() {
+                        let _ = downstream.send(span);
+                    }
+                }
+            })
+            .expect("Failed to spawn metrics thread");
+        span_tx
+    }
+
+    /// Forget all observations
+    pub fn clear(&self) {
+        self.lock().series.clear();
+    }
+
+    /// Write the metrics in the Prometheus text exposition format
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(self.render().as_bytes())
+    }
+
+    /// Write the metrics to `path`, replacing it in one step so that a
+    /// collector reading the file never sees it half written
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, self.render())?;
+        fs::rename(&tmp, path)
+    }
+
+    /// The metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let inner = self.lock();
+        let ns = &inner.namespace;
+        let label_names: Vec<String> = std::iter::once("operation".to_string())
+            .chain(inner.tag_keys.iter().map(|k| label_name(k)))
+            .collect();
+        let labels = |(operation, tags): &SeriesKey, extra: Option<String>| {
+            let values = std::iter::once(operation).chain(tags.iter());
+            let mut pairs: Vec<String> = label_names
+                .iter()
+                .zip(values)
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+                .collect();
+            pairs.extend(extra);
+            format!("{{{}}}", pairs.join(","))
+        };
+        let mut out = String::new();
+        out.push_str(&format!(
+            "# HELP {ns}_spans_total Finished spans per operation.\n# TYPE {ns}_spans_total counter\n",
+            ns = ns
+        ));
+        for (key, series) in inner.series.iter() {
+            out.push_str(&format!(
+                "{}_spans_total{} {}\n",
+                ns,
+                labels(key, None),
+                series.count
+            ));
+        }
+        out.push_str(&format!(
+            "# HELP {ns}_span_errors_total Finished spans tagged as errors per operation.\n# TYPE {ns}_span_errors_total counter\n",
+            ns = ns
+        ));
+        for (key, series) in inner.series.iter() {
+            out.push_str(&format!(
+                "{}_span_errors_total{} {}\n",
+                ns,
+                labels(key, None),
+                series.errors
+            ));
+        }
+        out.push_str(&format!(
+            "# HELP {ns}_span_duration_seconds Span durations per operation.\n# TYPE {ns}_span_duration_seconds histogram\n",
+            ns = ns
+        ));
+        for (key, series) in inner.series.iter() {
+            let mut cumulative = 0;
+            for (le, count) in inner.buckets.iter().zip(series.buckets.iter()) {
+                cumulative += count;
+                let le = Some(format!("le=\"{}\"", le));
+                out.push_str(&format!(
+                    "{}_span_duration_seconds_bucket{} {}\n",
+                    ns,
+                    labels(key, le),
+                    cumulative
+                ));
+            }
+            let le = Some("le=\"+Inf\"".to_string());
+            out.push_str(&format!(
+                "{}_span_duration_seconds_bucket{} {}\n",
+                ns,
+                labels(key, le),
+                series.count
+            ));
+            out.push_str(&format!(
+                "{}_span_duration_seconds_sum{} {}\n",
+                ns,
+                labels(key, None),
+                series.sum_seconds
+            ));
+            out.push_str(&format!(
+                "{}_span_duration_seconds_count{} {}\n",
+                ns,
+                labels(key, None),
+                series.count
+            ));
+        }
+        out
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Prometheus label names may only contain letters, digits and underscores
+fn label_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer_console::ConsoleReporter;
+    use crate::{AllSampler, Tracer};
+    use std::time::{Duration, Instant, SystemTime};
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new("test")
+            .with_tags(vec![conventions::ZOME_NAME])
+            .with_buckets(&[0.01, 0.1]);
+        let (tracer, span_rx) = Tracer::new(AllSampler);
+        let t0 = SystemTime::now();
+        for (zome, millis, failed) in
+            [("chat", 5, false), ("chat", 50, true), ("a\"b", 500, false)].iter()
+        {
+            let mut span = tracer
+                .span("call")
+                .tag(conventions::zome_name(zome))
+                .start_time(t0)
+                .start();
+            span.set_finish_time(|| t0 + Duration::from_millis(*millis));
+            if *failed {
+                span.set_tag(conventions::error);
+            }
+        }
+        for span in span_rx.try_iter() {
+            metrics.observe(&span);
+        }
+        let text = metrics.render();
+        let chat = "operation=\"call\",holochain_zome_name=\"chat\"";
+        let quoted = "operation=\"call\",holochain_zome_name=\"a\\\"b\"";
+        for line in [
+            "# TYPE test_spans_total counter".to_string(),
+            format!("test_spans_total{{{}}} 2", chat),
+            format!("test_spans_total{{{}}} 1", quoted),
+            format!("test_span_errors_total{{{}}} 1", chat),
+            format!("test_span_errors_total{{{}}} 0", quoted),
+            "# TYPE test_span_duration_seconds histogram".to_string(),
+            format!(
+                "test_span_duration_seconds_bucket{{{},le=\"0.01\"}} 1",
+                chat
+            ),
+            format!("test_span_duration_seconds_bucket{{{},le=\"0.1\"}} 2", chat),
+            format!(
+                "test_span_duration_seconds_bucket{{{},le=\"+Inf\"}} 2",
+                chat
+            ),
+            format!(
+                "test_span_duration_seconds_bucket{{{},le=\"0.1\"}} 0",
+                quoted
+            ),
+            format!("test_span_duration_seconds_sum{{{}}} 0.055", chat),
+            format!("test_span_duration_seconds_count{{{}}} 1", quoted),
+        ]
+        .iter()
+        {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_forward_and_file() {
+        let metrics = Metrics::new("test");
+        let (console_tx, console_rx) = crossbeam_channel::unbounded();
+        let mut reporter = ConsoleReporter::new(console_rx);
+        let tracer = Tracer::with_sender(AllSampler, metrics.forward(Some(console_tx)));
+        for _ in 0..3 {
+            drop(tracer.span("op").start());
+        }
+        drop(tracer);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while reporter.len() < 3 && Instant::now() < deadline {
+            reporter.drain();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(reporter.len(), 3);
+        let path = std::env::temp_dir().join(format!("span-metrics-{}.prom", std::process::id()));
+        metrics.write_to_file(&path).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(text.contains("test_spans_total{operation=\"op\"} 3\n"));
+        assert_eq!(text, metrics.render());
+    }
+}
@@ -0,0 +1,253 @@
+//! Drawing of the console tree with box-drawing connectors, colors and
+//! wrapping, for `TreeStyle::Unicode` and `TreeStyle::Ascii`. The reporter
+//! works out what to show for each span as a `Line`, and this module only
+//! decides how it looks.
+
+use crate::tracer_console::TreeStyle;
+use std::io::{self, Write};
+
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// One span, or a run of collapsed spans, of the tree
+#[derive(Debug, Default)]
+pub(crate) struct Line {
+    pub depth: usize,
+    /// Follows from the span it hangs off, rather than being its child
+    pub follower: bool,
+    /// Timing columns, or empty
+    pub columns: String,
+    /// Blank space as wide as `columns`
+    pub padding: String,
+    pub slow: bool,
+    pub error: bool,
+    /// e.g. `(orphan) `
+    pub marker: String,
+    pub name: String,
+    /// `key=value` for each tag
+    pub tags: Vec<String>,
+    pub logs: Vec<String>,
+}
+
+/// Connector strings, all three columns wide
+struct Connectors {
+    child: &'static str,
+    last_child: &'static str,
+    follower: &'static str,
+    last_follower: &'static str,
+    pipe: &'static str,
+    blank: &'static str,
+}
+
+const UNICODE: Connectors = Connectors {
+    child: "├─ ",
+    last_child: "└─ ",
+    follower: "├┄ ",
+    last_follower: "└┄ ",
+    pipe: "│  ",
+    blank: "   ",
+};
+
+const ASCII: Connectors = Connectors {
+    child: "|- ",
+    last_child: "`- ",
+    follower: "|~ ",
+    last_follower: "`~ ",
+    pipe: "|  ",
+    blank: "   ",
+};
+
+struct Palette(bool);
+
+impl Palette {
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.0 && !text.is_empty() {
+            format!("{}{}{}", code, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+/// Write `lines`, given in tree order with each line's depth, as a tree.
+/// Tags wrap onto continuation lines past `width` columns.
+pub(crate) fn write_lines(
+    w: &mut dyn Write,
+    lines: &[Line],
+    style: TreeStyle,
+    color: bool,
+    width: Option<usize>,
+) -> io::Result<()> {
+    let connectors = match style {
+        TreeStyle::Ascii => &ASCII,
+        _ => &UNICODE,
+    };
+    let palette = Palette(color);
+    let last = last_flags(lines);
+    // Whether each level still has siblings to come below the current line
+    let mut open: Vec<bool> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        open.truncate(line.depth);
+        let mut prefix = String::new();
+        for level in open.iter().skip(1) {
+            prefix.push_str(if *level {
+                connectors.pipe
+            } else {
+                connectors.blank
+            });
+        }
+        let connector = match (line.depth, line.follower, last[i]) {
+            (0, _, _) => "",
+            (_, false, false) => connectors.child,
+            (_, false, true) => connectors.last_child,
+            (_, true, false) => connectors.follower,
+            (_, true, true) => connectors.last_follower,
+        };
+        // What continues below this line: its own level, then its children
+        let mut below = prefix.clone();
+        if line.depth > 0 {
+            below.push_str(if last[i] {
+                connectors.blank
+            } else {
+                connectors.pipe
+            });
+        }
+        let has_children = lines.get(i + 1).iter().any(|next| next.depth > line.depth);
+        below.push_str(if has_children {
+            connectors.pipe
+        } else {
+            connectors.blank
+        });
+        open.resize(line.depth, false);
+        open.push(!last[i]);
+
+        let connector_text = if line.follower {
+            palette.paint(CYAN, connector)
+        } else {
+            connector.to_string()
+        };
+        let columns = if line.slow {
+            palette.paint(YELLOW, &line.columns)
+        } else {
+            line.columns.clone()
+        };
+        let name = if line.error {
+            palette.paint(RED, &line.name)
+        } else {
+            line.name.clone()
+        };
+        let head = format!(
+            "{}{}{}{}{}",
+            columns, prefix, connector_text, line.marker, name
+        );
+        let head_width = line.columns.chars().count()
+            + prefix.chars().count()
+            + connector.chars().count()
+            + line.marker.chars().count()
+            + line.name.chars().count();
+        let continuation = format!("{}{}", line.padding, below);
+        let continuation_width = continuation.chars().count();
+        write!(w, "{}", head)?;
+        let mut used = head_width;
+        let mut first_on_line = false;
+        for tag in line.tags.iter() {
+            let tag_width = tag.chars().count();
+            let fits = width.iter().all(|width| used + 1 + tag_width <= *width);
+            if !fits && !first_on_line {
+                writeln!(w)?;
+                write!(w, "{}", continuation)?;
+                used = continuation_width;
+                first_on_line = true;
+            }
+            if first_on_line {
+                write!(w, "{}", palette.paint(DIM, tag))?;
+                used += tag_width;
+                first_on_line = false;
+            } else {
+                write!(w, " {}", palette.paint(DIM, tag))?;
+                used += 1 + tag_width;
+            }
+        }
+        writeln!(w)?;
+        for log in line.logs.iter() {
+            writeln!(w, "{}{}", continuation, palette.paint(DIM, log))?;
+        }
+    }
+    Ok(())
+}
+
+/// For each line, whether no sibling comes after it, i.e. whether the next
+/// line at its depth or above is above it
+fn last_flags(lines: &[Line]) -> Vec<bool> {
+    let mut last = vec![true; lines.len()];
+    // The most recent line at each depth, still waiting for a sibling
+    let mut waiting: Vec<Option<usize>> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        // Deeper lines waiting for siblings won't get any now
+        waiting.truncate(line.depth + 1);
+        waiting.resize(line.depth + 1, None);
+        if let Some(previous) = waiting[line.depth] {
+            last[previous] = false;
+        }
+        waiting[line.depth] = Some(i);
+    }
+    last
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(depth: usize, name: &str) -> Line {
+        Line {
+            depth,
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn render(lines: &[Line], style: TreeStyle, color: bool, width: Option<usize>) -> String {
+        let mut out = Vec::new();
+        write_lines(&mut out, lines, style, color, width).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_connectors_and_wrapping() {
+        let mut lines = vec![
+            line(0, "root"),
+            line(1, "a"),
+            line(2, "a1"),
+            line(1, "b"),
+            line(1, "c"),
+            line(0, "other"),
+        ];
+        lines[1].tags = vec!["key=value".into(), "long=tag value".into(), "x=1".into()];
+        lines[2].logs = vec!["event: hello".into()];
+        lines[4].follower = true;
+        assert_eq!(
+            render(&lines, TreeStyle::Unicode, false, Some(20)),
+            "root\n├─ a key=value\n│  │  long=tag value\n│  │  x=1\n│  └─ a1\n│        event: hello\n├─ b\n└┄ c\nother\n"
+        );
+        assert_eq!(
+            render(&lines, TreeStyle::Ascii, false, None),
+            "root\n|- a key=value long=tag value x=1\n|  `- a1\n|        event: hello\n|- b\n`~ c\nother\n"
+        );
+    }
+
+    #[test]
+    fn test_colors() {
+        let mut lines = vec![line(0, "root"), line(1, "failed"), line(0, "slow")];
+        lines[1].error = true;
+        lines[1].follower = true;
+        lines[2].slow = true;
+        lines[2].columns = "1.000ms ".into();
+        assert_eq!(
+            render(&lines, TreeStyle::Unicode, true, None),
+            "root\n\u{1b}[36m└┄ \u{1b}[0m\u{1b}[31mfailed\u{1b}[0m\n\u{1b}[33m1.000ms \u{1b}[0mslow\n"
+        );
+    }
+}
@@ -0,0 +1,424 @@
+//! Programmatic access to collected spans, for tests and tooling which need
+//! more than a printed tree. A `SpanQuery` filters spans by name, tags, trace
+//! and timing, and a `SpanGraph` walks the relationships between them.
+//! `ConsoleReporter::query` and `ConsoleReporter::graph` run them over the
+//! spans a reporter has collected.
+
+use crate::FinishedSpan;
+use rustracing::span::SpanReference;
+use rustracing::tag::TagValue;
+use rustracing_jaeger::span::TraceId;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Criteria for selecting spans. Every criterion which is set must match.
+#[derive(Clone, Debug, Default)]
+pub struct SpanQuery {
+    name: Option<String>,
+    tags: Vec<(Cow<'static, str>, Option<TagValue>)>,
+    trace_id: Option<TraceId>,
+    started_after: Option<SystemTime>,
+    finished_before: Option<SystemTime>,
+    min_duration: Option<Duration>,
+}
+
+impl SpanQuery {
+    /// A query matching every span
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Only spans whose operation name matches `pattern`, where `*` matches
+    /// any run of characters, e.g. `"zome_call*"`
+    pub fn name<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.name = Some(pattern.into());
+        self
+    }
+
+    /// Only spans with a tag `key` equal to `value`
+    pub fn tag<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<Cow<'static, str>>,
+        V: Into<TagValue>,
+    {
+        self.tags.push((key.into(), Some(value.into())));
+        self
+    }
+
+    /// Only spans with a tag `key`, whatever its value
+    pub fn has_tag<K: Into<Cow<'static, str>>>(mut self, key: K) -> Self {
+        self.tags.push((key.into(), None));
+        self
+    }
+
+    /// Only spans in the trace `trace_id`
+    pub fn trace_id(mut self, trace_id: TraceId) -> Self {
+        self.trace_id = Some(trace_id);
+        self
+    }
+
+    /// Only spans which started at or after `time`
+    pub fn started_after(mut self, time: SystemTime) -> Self {
+        self.started_after = Some(time);
+        self
+    }
+
+    /// Only spans which finished at or before `time`
+    pub fn finished_before(mut self, time: SystemTime) -> Self {
+        self.finished_before = Some(time);
+        self
+    }
+
+    /// Only spans which took at least `duration`
+    pub fn min_duration(mut self, duration: Duration) -> Self {
+        self.min_duration = Some(duration);
+        self
+    }
+
+    /// Whether `span` meets every criterion
+    pub fn matches(&self, span: &FinishedSpan) -> bool {
+        if let Some(pattern) = &self.name {
+            if !glob_match(pattern, span.operation_name()) {
+                return false;
+            }
+        }
+        let has_tag = |(key, value): &(Cow<'static, str>, Option<TagValue>)| {
+            span.tags()
+                .iter()
+                .any(|t| t.name() == key && value.iter().all(|v| t.value() == v))
+        };
+        if !self.tags.iter().all(has_tag) {
+            return false;
+        }
+        if let Some(trace_id) = self.trace_id {
+            if span.context().state().trace_id() != trace_id {
+                return false;
+            }
+        }
+        if let Some(time) = self.started_after {
+            if span.start_time() < time {
+                return false;
+            }
+        }
+        if let Some(time) = self.finished_before {
+            if span.finish_time() > time {
+                return false;
+            }
+        }
+        if let Some(min) = self.min_duration {
+            let duration = span
+                .finish_time()
+                .duration_since(span.start_time())
+                .unwrap_or_default();
+            if duration < min {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// The matching spans, ordered by start time
+    pub fn filter<'a, I>(&self, spans: I) -> Vec<&'a FinishedSpan>
+    where
+        I: IntoIterator<Item = &'a FinishedSpan>,
+    {
+        let mut matching: Vec<_> = spans.into_iter().filter(|s| self.matches(s)).collect();
+        sort_spans(&mut matching);
+        matching
+    }
+}
+
+/// A snapshot of collected spans, indexed to walk from any span to the spans
+/// it references and the spans referencing it. Spans referencing spans which
+/// are not in the snapshot are treated as roots.
+#[derive(Debug, Default)]
+pub struct SpanGraph {
+    spans: HashMap<u64, Arc<FinishedSpan>>,
+    children: HashMap<u64, Vec<u64>>,
+    followers: HashMap<u64, Vec<u64>>,
+}
+
+impl SpanGraph {
+    /// Index `spans`
+    pub fn new<I: IntoIterator<Item = Arc<FinishedSpan>>>(spans: I) -> Self {
+        let spans: HashMap<_, _> = spans.into_iter().map(|s| (span_id(&s), s)).collect();
+        let mut children: HashMap<u64, Vec<u64>> = HashMap::new();
+        let mut followers: HashMap<u64, Vec<u64>> = HashMap::new();
+        for (id, span) in spans.iter() {
+            for reference in span.references() {
+                let map = match reference {
+                    SpanReference::ChildOf(_) => &mut children,
+                    SpanReference::FollowsFrom(_) => &mut followers,
+                };
+                map.entry(reference.span().span_id()).or_default().push(*id);
+            }
+        }
+        let key = |id: &u64| (spans[id].start_time(), *id);
+        for ids in children.values_mut().chain(followers.values_mut()) {
+            ids.sort_by_key(key);
+        }
+        SpanGraph {
+            spans,
+            children,
+            followers,
+        }
+    }
+
+    /// Number of spans in the snapshot
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    /// True if the snapshot has no spans
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// The span with id `span_id`
+    pub fn get(&self, span_id: u64) -> Option<&FinishedSpan> {
+        self.spans.get(&span_id).map(|s| &**s)
+    }
+
+    /// All spans, ordered by start time
+    pub fn spans(&self) -> Vec<&FinishedSpan> {
+        let mut spans: Vec<_> = self.spans.values().map(|s| &**s).collect();
+        sort_spans(&mut spans);
+        spans
+    }
+
+    /// The spans matching `query`, ordered by start time
+    pub fn query(&self, query: &SpanQuery) -> Vec<&FinishedSpan> {
+        query.filter(self.spans.values().map(|s| &**s))
+    }
+
+    /// The spans which don't reference any span in the snapshot, ordered by start time
+    pub fn roots(&self) -> Vec<&FinishedSpan> {
+        let mut roots: Vec<_> = self
+            .spans
+            .values()
+            .filter(|s| {
+                s.references()
+                    .iter()
+                    .all(|r| !self.spans.contains_key(&r.span().span_id()))
+            })
+            .map(|s| &**s)
+            .collect();
+        sort_spans(&mut roots);
+        roots
+    }
+
+    /// The span `span_id` is a child of
+    pub fn parent(&self, span_id: u64) -> Option<&FinishedSpan> {
+        self.referenced(span_id, true)
+    }
+
+    /// The span `span_id` follows from
+    pub fn followed(&self, span_id: u64) -> Option<&FinishedSpan> {
+        self.referenced(span_id, false)
+    }
+
+    /// The children of `span_id`, ordered by start time
+    pub fn children(&self, span_id: u64) -> Vec<&FinishedSpan> {
+        self.lookup(self.children.get(&span_id))
+    }
+
+    /// The spans following from `span_id`, ordered by start time
+    pub fn followers(&self, span_id: u64) -> Vec<&FinishedSpan> {
+        self.lookup(self.followers.get(&span_id))
+    }
+
+    /// The parent of `span_id`, its parent, and so on up to the root of the tree
+    pub fn ancestors(&self, span_id: u64) -> Vec<&FinishedSpan> {
+        let mut ancestors = Vec::new();
+        let mut seen = HashSet::new();
+        seen.insert(span_id);
+        let mut current = span_id;
+        while let Some(parent) = self.parent(current) {
+            current = span_id_of(parent);
+            // A cycle would otherwise never end
+            if !seen.insert(current) {
+                break;
+            }
+            ancestors.push(parent);
+        }
+        ancestors
+    }
+
+    /// Every span reachable from `span_id` through children and followers,
+    /// depth first and in start order at each level
+    pub fn descendants(&self, span_id: u64) -> Vec<&FinishedSpan> {
+        let mut descendants = Vec::new();
+        let mut seen = HashSet::new();
+        seen.insert(span_id);
+        let mut pending = vec![span_id];
+        while let Some(id) = pending.pop() {
+            if id != span_id {
+                descendants.extend(self.get(id));
+            }
+            let next = self.children.get(&id).into_iter().flatten();
+            let next = next.chain(self.followers.get(&id).into_iter().flatten());
+            let mut next: Vec<u64> = next.copied().filter(|id| seen.insert(*id)).collect();
+            next.reverse();
+            pending.extend(next);
+        }
+        descendants
+    }
+
+    fn referenced(&self, span_id: u64, child_of: bool) -> Option<&FinishedSpan> {
+        self.spans
+            .get(&span_id)?
+            .references()
+            .iter()
+            .filter(|r| r.is_child_of() == child_of)
+            .find_map(|r| self.get(r.span().span_id()))
+    }
+
+    fn lookup(&self, ids: Option<&Vec<u64>>) -> Vec<&FinishedSpan> {
+        ids.into_iter()
+            .flatten()
+            .filter_map(|id| self.get(*id))
+            .collect()
+    }
+}
+
+fn span_id(span: &Arc<FinishedSpan>) -> u64 {
+    span_id_of(span)
+}
+
+fn span_id_of(span: &FinishedSpan) -> u64 {
+    span.context().state().span_id()
+}
+
+fn sort_spans(spans: &mut Vec<&FinishedSpan>) {
+    spans.sort_by_key(|s| (s.start_time(), span_id_of(s)));
+}
+
+/// Match `text` against `pattern`, where `*` matches any run of characters
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    if !text.starts_with(first) {
+        return false;
+    }
+    let mut rest = &text[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // No `*` at all, so the whole text must equal the pattern
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer_console::new_tracer_with_console_reporter;
+    use crate::Tag;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("call", "call"));
+        assert!(!glob_match("call", "calls"));
+        assert!(glob_match("call*", "call_zome"));
+        assert!(glob_match("*zome*", "call_zome_fn"));
+        assert!(glob_match("a*b*c", "abbc"));
+        assert!(!glob_match("a*b*c", "acb"));
+        assert!(!glob_match("ab*ba", "aba"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn test_query_and_walk() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        let t0 = SystemTime::now();
+        let ms = Duration::from_millis;
+        let (root_id, child_id, grandchild_id, follower_id, trace_id) = {
+            let mut root = tracer.span("root").start_time(t0).start();
+            root.set_finish_time(|| t0 + ms(20));
+            let mut child = root.child("call_zome", |o| {
+                o.start_time(t0 + ms(1))
+                    .tag(Tag::new("zome", "chat"))
+                    .start()
+            });
+            child.set_finish_time(|| t0 + ms(11));
+            let mut grandchild = child.child("call_fn", |o| o.start_time(t0 + ms(2)).start());
+            grandchild.set_finish_time(|| t0 + ms(3));
+            let mut follower = root.follower("send", |o| {
+                o.start_time(t0 + ms(12))
+                    .tag(Tag::new("retries", 2))
+                    .start()
+            });
+            follower.set_finish_time(|| t0 + ms(13));
+            let state = |s: &rustracing_jaeger::Span| s.context().unwrap().state().clone();
+            (
+                state(&root).span_id(),
+                state(&child).span_id(),
+                state(&grandchild).span_id(),
+                state(&follower).span_id(),
+                state(&root).trace_id(),
+            )
+        };
+        let mut other = tracer.span("other").start_time(t0 + ms(30)).start();
+        other.set_finish_time(|| t0 + ms(31));
+        drop(other);
+        reporter.drain();
+        let names = |spans: Vec<&FinishedSpan>| {
+            spans
+                .iter()
+                .map(|s| s.operation_name().to_string())
+                .collect::<Vec<_>>()
+        };
+        let q = |query: SpanQuery| {
+            reporter
+                .query(&query)
+                .iter()
+                .map(|s| s.operation_name().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(q(SpanQuery::new().name("call_*")), ["call_zome", "call_fn"]);
+        assert_eq!(q(SpanQuery::new().tag("zome", "chat")), ["call_zome"]);
+        assert_eq!(q(SpanQuery::new().tag("retries", 2)), ["send"]);
+        assert!(q(SpanQuery::new().tag("retries", "2")).is_empty());
+        assert_eq!(q(SpanQuery::new().has_tag("zome")), ["call_zome"]);
+        assert_eq!(q(SpanQuery::new().trace_id(trace_id)).len(), 4);
+        assert_eq!(
+            q(SpanQuery::new().min_duration(ms(10))),
+            ["root", "call_zome"]
+        );
+        assert_eq!(
+            q(SpanQuery::new()
+                .started_after(t0 + ms(1))
+                .finished_before(t0 + ms(11))),
+            ["call_zome", "call_fn"]
+        );
+
+        let graph = reporter.graph();
+        assert_eq!(graph.len(), 5);
+        assert_eq!(names(graph.roots()), ["root", "other"]);
+        assert_eq!(names(graph.children(root_id)), ["call_zome"]);
+        assert_eq!(names(graph.followers(root_id)), ["send"]);
+        assert_eq!(names(graph.ancestors(grandchild_id)), ["call_zome", "root"]);
+        assert_eq!(
+            names(graph.descendants(root_id)),
+            ["call_zome", "call_fn", "send"]
+        );
+        assert_eq!(
+            graph.parent(child_id).map(|s| s.operation_name()),
+            Some("root")
+        );
+        assert_eq!(
+            graph.followed(follower_id).map(|s| s.operation_name()),
+            Some("root")
+        );
+        assert!(graph.parent(follower_id).is_none());
+    }
+}
@@ -0,0 +1,146 @@
+//! Finished spans can't be constructed directly, so spans read back from files
+//! are started and finished again through a tracer of their own. Also helpers
+//! for reading the JSON formats spans are written in.
+
+use crate::{AllSampler, FinishedSpan, Tracer};
+use rustracing::log::LogField;
+use rustracing::span::SpanContext;
+use rustracing::tag::{Tag, TagValue};
+use rustracing_jaeger::span::{SpanContextState, SpanContextStateBuilder, TraceId};
+use serde_json::Value;
+use std::time::SystemTime;
+
+/// Everything a finished span is made of
+pub(crate) struct SpanParts {
+    pub state: SpanContextState,
+    pub operation: String,
+    pub start: SystemTime,
+    pub finish: SystemTime,
+    /// Each referenced span, and whether it is a child-of reference
+    pub references: Vec<(bool, SpanContextState)>,
+    pub tags: Vec<Tag>,
+    pub logs: Vec<(SystemTime, Vec<LogField>)>,
+}
+
+impl SpanParts {
+    /// The parts of an existing span, e.g. to build it again with changes
+    pub fn of(span: &FinishedSpan) -> Self {
+        SpanParts {
+            state: span.context().state().clone(),
+            operation: span.operation_name().to_owned(),
+            start: span.start_time(),
+            finish: span.finish_time(),
+            references: span
+                .references()
+                .iter()
+                .map(|r| (r.is_child_of(), r.span().clone()))
+                .collect(),
+            tags: span.tags().to_vec(),
+            logs: span
+                .logs()
+                .iter()
+                .map(|log| {
+                    let fields = log
+                        .fields()
+                        .iter()
+                        .map(|f| LogField::new(f.name().to_owned(), f.value().to_owned()))
+                        .collect();
+                    (log.time(), fields)
+                })
+                .collect(),
+        }
+    }
+}
+
+pub(crate) struct Rebuilder {
+    tracer: Tracer,
+    span_rx: crossbeam_channel::Receiver<FinishedSpan>,
+}
+
+impl Rebuilder {
+    pub fn new() -> Self {
+        let (span_tx, span_rx) = crossbeam_channel::unbounded();
+        Rebuilder {
+            tracer: Tracer::with_sender(AllSampler, span_tx),
+            span_rx,
+        }
+    }
+
+    pub fn build(&self, parts: SpanParts) -> FinishedSpan {
+        let references: Vec<_> = parts
+            .references
+            .into_iter()
+            .map(|(child_of, state)| (child_of, SpanContext::new(state, vec![])))
+            .collect();
+        let mut options = self.tracer.span(parts.operation).start_time(parts.start);
+        for (child_of, context) in references.iter() {
+            options = if *child_of {
+                options.child_of(context)
+            } else {
+                options.follows_from(context)
+            };
+        }
+        let mut span = options.start_with_state(parts.state);
+        // Set afterwards, since start options would sort the tags
+        let tags = parts.tags;
+        span.set_tags(|| tags);
+        for (time, fields) in parts.logs {
+            span.log(|l| {
+                l.time(time);
+                for field in fields {
+                    l.field(field);
+                }
+            });
+        }
+        let finish = parts.finish;
+        span.set_finish_time(|| finish);
+        drop(span);
+        self.span_rx
+            .try_recv()
+            .expect("Rebuilt span is sent on drop")
+    }
+}
+
+/// The array under `key`, or nothing if there isn't one
+pub(crate) fn array<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    value[key]
+        .as_array()
+        .map(|values| values.as_slice())
+        .unwrap_or(&[])
+}
+
+pub(crate) fn field_str<'a>(value: &'a Value, key: &str) -> Result<&'a str, String> {
+    value[key]
+        .as_str()
+        .ok_or_else(|| format!("missing string field {:?}", key))
+}
+
+/// The ids under `trace_key` and `span_key`, both in hex
+pub(crate) fn context_state(
+    value: &Value,
+    trace_key: &str,
+    span_key: &str,
+) -> Result<SpanContextState, String> {
+    let trace_id: TraceId = field_str(value, trace_key)?
+        .parse()
+        .map_err(|_| format!("invalid {} {}", trace_key, value[trace_key]))?;
+    let span_id = u64::from_str_radix(field_str(value, span_key)?, 16)
+        .map_err(|_| format!("invalid {} {}", span_key, value[span_key]))?;
+    Ok(SpanContextStateBuilder::new()
+        .trace_id(trace_id)
+        .span_id(span_id)
+        .finish())
+}
+
+/// Numbers which fit an `i64` become integer tags, any others float tags
+pub(crate) fn tag_value(value: &Value) -> Result<TagValue, String> {
+    match value {
+        Value::String(s) => Ok(TagValue::from(s.clone())),
+        Value::Bool(b) => Ok(TagValue::from(*b)),
+        Value::Number(n) => Ok(match n.as_i64() {
+            Some(i) => TagValue::from(i),
+            None => TagValue::from(n.as_f64().unwrap_or_default()),
+        }),
+        other => Err(format!("unsupported tag value {}", other)),
+    }
+}
@@ -1,3 +1,4 @@
+use crate::deterministic;
 use crate::span_context::HSpanContext;
 use crate::span_wrap::SpanWrap;
 use rustracing::sampler::*;
@@ -5,6 +6,7 @@ use rustracing::span::StartSpanOptions;
 use rustracing_jaeger::Span as RjSpan;
 use rustracing_jaeger::{span::SpanContextState, Tracer};
 use std::borrow::Cow;
+use std::time::SystemTime;
 
 lazy_static! {
     pub(crate) static ref NOOP_SPAN: HSpan = HSpan::noop();
@@ -27,17 +29,25 @@ impl From<RjSpan> for HSpan {
 
 impl HSpan {
     pub fn event<S: Into<Cow<'static, str>>>(&mut self, msg: S) {
+        let time = self.log_time();
         self.0.log(|l| {
-            l.std().event(msg);
+            l.time(time).std().event(msg);
         })
     }
 
     pub fn error<S: Into<Cow<'static, str>>>(&mut self, kind: S, msg: S) {
+        let time = self.log_time();
         self.0.log(|l| {
-            l.error().kind(kind).message(msg);
+            l.time(time).error().kind(kind).message(msg);
         })
     }
 
+    /// The time to log at, from the clock of a `DeterministicTracer` if this
+    /// span was started from one
+    pub(crate) fn log_time(&self) -> SystemTime {
+        deterministic::now(self.0.context()).unwrap_or_else(SystemTime::now)
+    }
+
     pub fn context(&self) -> Option<HSpanContext> {
         self.0.context().map(|ctx| HSpanContext(ctx.to_owned()))
     }
@@ -60,12 +70,22 @@ impl HSpan {
 
     /// Call underlying `child` method with only a simple operation name
     pub fn child<S: Into<Cow<'static, str>>>(&self, operation_name: S) -> Self {
-        self.0.child(operation_name, |o| o.start()).into()
+        let parent = self.0.context();
+        self.0
+            .child(operation_name, |o| {
+                deterministic::start_span(o, parent, None)
+            })
+            .into()
     }
 
     /// Call underlying `follower` method with only a simple operation name
     pub fn follower<S: Into<Cow<'static, str>>>(&self, operation_name: S) -> Self {
-        self.0.follower(operation_name, |o| o.start()).into()
+        let parent = self.0.context();
+        self.0
+            .follower(operation_name, |o| {
+                deterministic::start_span(o, parent, None)
+            })
+            .into()
     }
 
     /// Wrap this span in a SpanWrap along with some user data
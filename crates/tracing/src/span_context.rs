@@ -1,3 +1,4 @@
+use crate::deterministic;
 use crate::rustracing::carrier::{ExtractFromBinary, InjectToBinary};
 use crate::span::HSpan;
 use crate::span_wrap::SpanWrap;
@@ -31,11 +32,8 @@ impl HSpanContext {
         tracer: &Tracer,
         operation_name: S,
     ) -> HSpan {
-        tracer
-            .span(operation_name)
-            .follows_from(&self.0)
-            .start()
-            .into()
+        let options = tracer.span(operation_name).follows_from(&self.0);
+        deterministic::start_span(options, Some(&self.0), None).into()
     }
 
     pub fn follower_<'a, N: Into<Cow<'static, str>>, F>(
@@ -4,11 +4,20 @@
 //! to automatically push new child spans onto the stack when entering a new function frame.
 //! Using functions like `with_top` allow the user to access the stack directly, for situations
 //! like needing to take a span context and send it into another thread, or out of the process entirely.
+//!
+//! Async executors run many tasks on one thread, so a single thread-local stack would mix up
+//! the spans of unrelated tasks. A `TaskStack` gives a task its own stack, which is swapped in
+//! place of the thread's stack while the task is being polled (see the `task` module).
 
+use crate::deterministic::{self, start_span};
 use crate::span;
-use crate::Span;
+use crate::{Span, SpanContext};
+use rustracing_jaeger::span::TraceId;
+use rustracing_jaeger::{span::SpanHandle, Span as RjSpan};
+use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 /// This enum defines how to handle situations where we expect there to be a Span
 /// on the stack, but there is none.
@@ -28,6 +37,103 @@ thread_local! {
     static SPANSTACK: RefCell<SpanStack> = RefCell::new(SpanStack::default());
 }
 
+/// Global limits, stored as `UNLIMITED` for `None`
+static MAX_DEPTH: AtomicUsize = AtomicUsize::new(UNLIMITED);
+static MAX_SPANS_PER_TRACE: AtomicUsize = AtomicUsize::new(UNLIMITED);
+const UNLIMITED: usize = !0;
+
+/// Limits on how many spans `push_span_with` will create, to keep deeply recursive
+/// autotraced code from flooding the stack and the reporter. Once a limit is hit,
+/// `push_span_with` pushes a noop span instead, and the number of spans dropped is
+/// logged as an event on the root span of the trace when it is popped.
+/// `None` means no limit, and `Some(0)` that `push_span_with` creates no spans at all.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpanLimits {
+    /// Maximum number of spans on the stack
+    pub max_depth: Option<usize>,
+    /// Maximum number of spans pushed onto the stack for a single trace
+    pub max_spans_per_trace: Option<usize>,
+}
+
+impl SpanLimits {
+    /// No limits at all, the default
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.max_depth.is_none() && self.max_spans_per_trace.is_none()
+    }
+
+    fn exceeded(&self, depth: usize, spans: usize) -> bool {
+        self.max_depth.map(|max| depth >= max).unwrap_or(false)
+            || self
+                .max_spans_per_trace
+                .map(|max| spans >= max)
+                .unwrap_or(false)
+    }
+}
+
+/// Set the limits used by every stack, unless overridden with `with_span_limits`
+pub fn set_span_limits(limits: SpanLimits) {
+    MAX_DEPTH.store(store_limit(limits.max_depth), Ordering::Relaxed);
+    MAX_SPANS_PER_TRACE.store(store_limit(limits.max_spans_per_trace), Ordering::Relaxed);
+}
+
+fn global_span_limits() -> SpanLimits {
+    SpanLimits {
+        max_depth: load_limit(MAX_DEPTH.load(Ordering::Relaxed)),
+        max_spans_per_trace: load_limit(MAX_SPANS_PER_TRACE.load(Ordering::Relaxed)),
+    }
+}
+
+fn store_limit(limit: Option<usize>) -> usize {
+    limit.unwrap_or(UNLIMITED)
+}
+
+fn load_limit(stored: usize) -> Option<usize> {
+    match stored {
+        UNLIMITED => None,
+        n => Some(n),
+    }
+}
+
+/// The limits currently in effect for the current stack
+pub fn span_limits() -> SpanLimits {
+    SPANSTACK
+        .with(|stack| stack.borrow().limits)
+        .unwrap_or_else(global_span_limits)
+}
+
+/// Run `f` with different limits for the current stack only, i.e. for this
+/// thread, or for this task when running inside an instrumented future.
+/// The previous limits are restored afterwards, even if `f` panics.
+pub fn with_span_limits<A, F: FnOnce() -> A>(limits: SpanLimits, f: F) -> A {
+    let previous = SPANSTACK.with(|stack| stack.borrow_mut().limits.replace(limits));
+    let _restore = RestoreLimits(previous);
+    f()
+}
+
+/// Puts back the limits which `with_span_limits` replaced
+struct RestoreLimits(Option<SpanLimits>);
+
+impl Drop for RestoreLimits {
+    fn drop(&mut self) {
+        let previous = self.0;
+        SPANSTACK.with(|stack| stack.borrow_mut().limits = previous);
+    }
+}
+
+/// How many spans a trace has pushed onto a stack, and how many were dropped
+/// because of the limits. `root` is the stack index of the trace's first span.
+/// Budgets are only kept while limits are set, so a trace which was already
+/// on the stack when they were set is counted from its next span on.
+struct TraceBudget {
+    root: usize,
+    spans: usize,
+    dropped: usize,
+}
+
 /// Internal representation of a stack of Rc<Span>
 /// Keep this private! We're doing some careful management of Rc lifetimes here,
 /// it would be a shame if these Rc's were to leak out, destroying the guarantees
@@ -35,17 +141,69 @@ thread_local! {
 #[derive(Default)]
 struct SpanStack {
     stack: Vec<Span>,
+    /// For each span, the trace of the topmost recording span at or below it.
+    /// Noop spans pushed in place of dropped ones don't belong to any trace.
+    traces: Vec<Option<TraceId>>,
     guards: BTreeSet<usize>,
+    budgets: HashMap<TraceId, TraceBudget>,
+    limits: Option<SpanLimits>,
 }
 
 impl SpanStack {
     fn push_span(&mut self, span: Span) -> usize {
         let index = self.stack.len();
+        let own_trace = trace_id(&span);
+        if let Some(trace_id) = own_trace {
+            let limits = self.limits.unwrap_or_else(global_span_limits);
+            if !limits.is_unlimited() {
+                self.budgets
+                    .entry(trace_id)
+                    .or_insert(TraceBudget {
+                        root: index,
+                        spans: 0,
+                        dropped: 0,
+                    })
+                    .spans += 1;
+            }
+        }
+        let current = own_trace.or_else(|| self.current_trace());
+        self.traces.push(current);
         self.guards.insert(index);
         self.stack.push(span);
         index
     }
 
+    /// The trace of the topmost span which is actually recording
+    fn current_trace(&self) -> Option<TraceId> {
+        self.traces.last().and_then(|trace| *trace)
+    }
+
+    /// Check the limits before pushing a new span for the current trace.
+    /// Returns false, and counts the span as dropped, if a limit is exceeded.
+    fn admit(&mut self, limits: &SpanLimits) -> bool {
+        if limits.is_unlimited() {
+            return true;
+        }
+        let depth = self.stack.len();
+        let trace = self.current_trace();
+        let budget = trace.and_then(|t| self.budgets.get_mut(&t));
+        let spans = budget.as_ref().map(|b| b.spans).unwrap_or(0);
+        if !limits.exceeded(depth, spans) {
+            return true;
+        }
+        if let Some(budget) = budget {
+            if budget.dropped == 0 {
+                warn!(
+                    "Span limits {:?} exceeded in trace {}, dropping further spans",
+                    limits,
+                    trace.expect("budget implies trace")
+                );
+            }
+            budget.dropped += 1;
+        }
+        false
+    }
+
     /// Finds the size of the stack disregarding items on top which no longer
     /// have associated SpanStackGuards.
     fn live_length(&self) -> usize {
@@ -56,7 +214,32 @@ impl SpanStack {
         self.guards.remove(&index);
         let new_len = self.live_length();
         while self.stack.len() > new_len {
-            self.stack.pop();
+            let index = self.stack.len() - 1;
+            let mut span = self.stack.pop().expect("stack is longer than new_len");
+            self.traces.pop();
+            self.release_budget(index, &mut span);
+            deterministic::finish(&mut span);
+        }
+    }
+
+    /// When the root span of a trace is popped, forget the trace's budget,
+    /// and tell the root how many spans were dropped beneath it.
+    fn release_budget(&mut self, index: usize, span: &mut Span) {
+        if self.budgets.is_empty() {
+            return;
+        }
+        let trace_id = match trace_id(span) {
+            Some(trace_id) => trace_id,
+            None => return,
+        };
+        if self.budgets.get(&trace_id).map(|b| b.root) == Some(index) {
+            let budget = self.budgets.remove(&trace_id).expect("just checked");
+            if budget.dropped > 0 {
+                span.event(format!(
+                    "span limits exceeded, {} spans dropped",
+                    budget.dropped
+                ));
+            }
         }
     }
 
@@ -95,6 +278,10 @@ impl SpanStackGuard {
     }
 }
 
+fn trace_id(span: &Span) -> Option<TraceId> {
+    span.0.context().map(|c| c.state().trace_id())
+}
+
 impl Drop for SpanStackGuard {
     fn drop(&mut self) {
         SPANSTACK.with(|stack| stack.borrow_mut().prune(self.index));
@@ -119,9 +306,21 @@ pub fn push_span(span: Span) -> SpanStackGuard {
 
 /// Applies a function to the top of the span stack and pushes the value onto the stack.
 /// If the stack is empty, the function will not be executed and None will be returned.
+/// If pushing would exceed the current `SpanLimits`, the function will not be executed
+/// either, and a noop span is pushed in its place.
 pub fn push_span_with<F: FnOnce(&mut Span) -> Span>(f: F) -> Option<SpanStackGuard> {
     let maybe_guard = SPANSTACK
-        .with(|stack| stack.borrow_mut().top().map(f))
+        .with(|stack| {
+            let mut stack = stack.borrow_mut();
+            let limits = stack.limits.unwrap_or_else(global_span_limits);
+            if stack.is_empty() {
+                None
+            } else if stack.admit(&limits) {
+                stack.top().map(f)
+            } else {
+                Some(Span::noop())
+            }
+        })
         .map(SpanStackGuard::new);
     if maybe_guard.is_none() {
         handle_empty_stack("Using push_span_with but the span stack is empty! Using noop span.");
@@ -163,6 +362,93 @@ pub fn is_empty() -> bool {
     SPANSTACK.with(|stack| stack.borrow().is_empty())
 }
 
+/// A span stack belonging to a single async task rather than to a thread.
+/// While `enter` is running, every stack function (`with_top`, `push_span_with`,
+/// `top_follower`, ...) resolves against this stack instead of the thread's.
+#[derive(Default)]
+pub struct TaskStack {
+    stack: SpanStack,
+}
+
+impl TaskStack {
+    /// Create a task stack with `root` at the bottom. The root stays on the
+    /// stack for as long as the task stack exists.
+    pub fn new(root: Span) -> Self {
+        let mut stack = SpanStack::default();
+        stack.push_span(root);
+        TaskStack { stack }
+    }
+
+    /// Run `f` with this stack in place of the current thread's stack
+    pub fn enter<A, F: FnOnce() -> A>(&mut self, f: F) -> A {
+        let _swapped = SwappedStack::new(&mut self.stack);
+        f()
+    }
+}
+
+/// Holds a task's stack in the thread-local slot, and swaps the previous
+/// stack back on drop, even if the task panics.
+struct SwappedStack<'a>(&'a mut SpanStack);
+
+impl<'a> SwappedStack<'a> {
+    fn new(stack: &'a mut SpanStack) -> Self {
+        SPANSTACK.with(|current| std::mem::swap(&mut *current.borrow_mut(), stack));
+        SwappedStack(stack)
+    }
+}
+
+impl<'a> Drop for SwappedStack<'a> {
+    fn drop(&mut self) {
+        SPANSTACK.with(|current| std::mem::swap(&mut *current.borrow_mut(), self.0));
+    }
+}
+
+/// A sendable snapshot of the top of the span stack, used to carry the current
+/// trace into work that will run on another thread, e.g. a thread pool job.
+/// The worker restores it with `child` or `follower`, which push a new span onto
+/// the worker's own stack.
+#[derive(Clone, Debug)]
+pub struct SpanSnapshot(SpanHandle);
+
+impl SpanSnapshot {
+    /// A snapshot that restores to nothing, as if the stack had been empty
+    pub fn empty() -> Self {
+        SpanSnapshot(Span::noop().0.handle())
+    }
+
+    /// The context of the captured span, if the captured stack was not empty
+    pub fn context(&self) -> Option<SpanContext> {
+        self.0.context().cloned().map(SpanContext)
+    }
+
+    /// Push a child of the captured span onto the current thread's stack.
+    /// Returns None if the snapshot is empty.
+    pub fn child<S: Into<Cow<'static, str>>>(&self, name: S) -> Option<SpanStackGuard> {
+        self.restore(|handle| handle.child(name, |o| start_span(o, handle.context(), None)))
+    }
+
+    /// Push a follower of the captured span onto the current thread's stack.
+    /// Returns None if the snapshot is empty.
+    pub fn follower<S: Into<Cow<'static, str>>>(&self, name: S) -> Option<SpanStackGuard> {
+        self.restore(|handle| handle.follower(name, |o| start_span(o, handle.context(), None)))
+    }
+
+    fn restore<F: FnOnce(&SpanHandle) -> RjSpan>(&self, f: F) -> Option<SpanStackGuard> {
+        if self.0.is_sampled() {
+            Some(push_span(f(&self.0).into()))
+        } else {
+            handle_empty_stack("Restoring an empty SpanSnapshot! No span pushed.");
+            None
+        }
+    }
+}
+
+/// Capture the top of the span stack so it can be sent to another thread.
+/// If the stack is empty the snapshot is empty too.
+pub fn capture() -> SpanSnapshot {
+    with_top(|top| SpanSnapshot(top.0.handle())).unwrap_or_else(SpanSnapshot::empty)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -219,4 +505,126 @@ mod tests {
         }
         with_stack(|stack| assert_eq!(stack.len(), 0));
     }
+
+    #[test]
+    fn test_task_stack() {
+        let _g0 = push_span(Span::noop());
+        let mut task = TaskStack::new(Span::noop());
+        task.enter(|| {
+            let _g1 = push_span_with(|s| s.child("1"));
+            with_stack(|stack| assert_eq!(stack.len(), 2));
+        });
+        with_stack(|stack| assert_eq!(stack.len(), 1));
+        // A guard may be held across several polls of the same task
+        let g2 = task.enter(|| push_span_with(|s| s.child("2")));
+        with_stack(|stack| assert_eq!(stack.len(), 1));
+        task.enter(|| {
+            with_stack(|stack| assert_eq!(stack.len(), 2));
+            drop(g2);
+            with_stack(|stack| assert_eq!(stack.len(), 1));
+        });
+    }
+
+    #[test]
+    fn test_span_limits() {
+        let (span_tx, span_rx) = crossbeam_channel::unbounded();
+        let tracer = crate::Tracer::with_sender(crate::AllSampler, span_tx);
+        fn recurse(n: u32) {
+            let _g = push_span_with(|s| s.child(format!("recurse {}", n)));
+            if n > 0 {
+                recurse(n - 1);
+            }
+        }
+        let limits = SpanLimits {
+            max_depth: Some(4),
+            max_spans_per_trace: Some(5),
+        };
+        with_span_limits(limits, || {
+            let _g = push_span(tracer.span("root").start().into());
+            // Depth limit: root plus three recursive spans, five dropped
+            recurse(7);
+            // Trace budget: one more span, the other three dropped
+            recurse(3);
+        });
+        assert_eq!(span_limits(), SpanLimits::unlimited());
+        let spans: Vec<_> = span_rx.try_iter().collect();
+        assert_eq!(spans.len(), 5);
+        let root = spans.last().unwrap();
+        assert_eq!(root.operation_name(), "root");
+        let events: Vec<_> = root.logs().iter().map(|l| l.fields()[0].value()).collect();
+        assert_eq!(events, vec!["span limits exceeded, 8 spans dropped"]);
+    }
+
+    #[test]
+    fn test_span_limits_zero() {
+        let (span_tx, span_rx) = crossbeam_channel::unbounded();
+        let tracer = crate::Tracer::with_sender(crate::AllSampler, span_tx);
+        let nothing = SpanLimits {
+            max_depth: Some(0),
+            max_spans_per_trace: None,
+        };
+        // Stored globally, zero keeps its meaning
+        assert_eq!(load_limit(store_limit(Some(0))), Some(0));
+        assert_eq!(load_limit(store_limit(None)), None);
+        with_span_limits(nothing, || {
+            let _g = push_span(tracer.span("root").start().into());
+            let _child = push_span_with(|s| s.child("child"));
+        });
+        let names: Vec<_> = span_rx
+            .try_iter()
+            .map(|s| s.operation_name().to_owned())
+            .collect();
+        assert_eq!(names, vec!["root"]);
+        // Restored after a panic too
+        let result = std::panic::catch_unwind(|| with_span_limits(nothing, || panic!("in f")));
+        assert!(result.is_err());
+        assert_eq!(span_limits(), SpanLimits::unlimited());
+    }
+
+    #[test]
+    fn test_unlimited_keeps_no_budgets() {
+        let (span_tx, _span_rx) = crossbeam_channel::unbounded();
+        let tracer = crate::Tracer::with_sender(crate::AllSampler, span_tx);
+        let one_deep = SpanLimits {
+            max_depth: Some(1),
+            max_spans_per_trace: None,
+        };
+        with_span_limits(SpanLimits::unlimited(), || {
+            let _g = push_span(tracer.span("root").start().into());
+            let _child = push_span_with(|s| s.child("child"));
+            with_stack(|stack| assert!(stack.budgets.is_empty()));
+        });
+        with_span_limits(one_deep, || {
+            let _g = push_span(tracer.span("root").start().into());
+            let _noop = push_span_with(|s| s.child("dropped"));
+            let _deeper = push_span_with(|s| s.child("dropped"));
+            with_stack(|stack| {
+                // The noops still count against the root's trace
+                let trace = stack.current_trace().unwrap();
+                assert_eq!(stack.budgets[&trace].dropped, 2);
+            });
+        });
+        with_stack(|stack| assert!(stack.budgets.is_empty() && stack.traces.is_empty()));
+    }
+
+    #[test]
+    fn test_capture_restore() {
+        let (tracer, mut reporter) = crate::tracer_console::new_tracer_with_console_reporter();
+        let snapshot = {
+            let _g = push_span(tracer.span("root").start().into());
+            capture()
+        };
+        let trace_id = snapshot.context().unwrap().0.state().trace_id();
+        std::thread::spawn(move || {
+            let _g = snapshot.child("worker").expect("snapshot is not empty");
+            with_top(|top| {
+                assert_eq!(top.context().unwrap().0.state().trace_id(), trace_id);
+            });
+        })
+        .join()
+        .unwrap();
+        assert_eq!(reporter.drain(), 2);
+        assert!(capture().context().is_none());
+        assert!(SpanSnapshot::empty().follower("nothing").is_none());
+    }
 }
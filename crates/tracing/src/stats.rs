@@ -0,0 +1,541 @@
+//! Latency statistics per operation name, for a quick profile of a run from
+//! the spans it produces anyway, e.g. through `autotrace`. A `StatsReporter`
+//! drains finished spans from a tracer's channel like `ConsoleReporter` does,
+//! but keeps only per-operation samples of duration and self time, i.e. the
+//! duration minus the time spent in child spans. Count, min, max and mean are
+//! exact; percentiles come from a uniform sample of bounded size.
+
+use crate::conventions;
+use crate::tracer_console::{format_duration, span_duration};
+use crate::{AllSampler, FinishedSpan, Tracer};
+use rustracing::tag::TagValue;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime};
+
+/// Samples kept per operation unless set with `StatsReporter::with_max_samples`
+pub const DEFAULT_MAX_SAMPLES: usize = 10_000;
+
+/// How long a parent is waited for unless set with
+/// `StatsReporter::with_parent_timeout`
+pub const DEFAULT_PARENT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Create a Tracer and a Reporter which aggregates latency statistics of all spans
+pub fn new_tracer_with_stats_reporter() -> (Tracer, StatsReporter) {
+    let (span_tx, span_rx) = crossbeam_channel::bounded(1000);
+    let tracer = Tracer::with_sender(AllSampler, span_tx);
+    (tracer, StatsReporter::new(span_rx))
+}
+
+/// Order of the operations in a stats table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortBy {
+    Name,
+    Count,
+    /// Slowest mean duration first
+    Mean,
+    /// Slowest 99th percentile duration first
+    P99,
+    /// Most total self time first, i.e. where the time actually went
+    TotalSelfTime,
+}
+
+/// Summary of a set of latency samples. Percentiles are nearest-rank.
+/// Serializes with every field in microseconds, e.g. `p50_us`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Latency {
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+impl Latency {
+    fn from_samples(samples: &mut [Duration]) -> Self {
+        if samples.is_empty() {
+            return Default::default();
+        }
+        samples.sort_unstable();
+        let total: u128 = samples.iter().map(|d| d.as_nanos()).sum();
+        let mean = total / samples.len() as u128;
+        let percentile = |p: usize| {
+            // The smallest sample with at least p% of samples at or below it
+            let rank = (p * samples.len()).saturating_sub(1) / 100;
+            samples[rank]
+        };
+        Latency {
+            min: samples[0],
+            max: samples[samples.len() - 1],
+            mean: Duration::new((mean / 1_000_000_000) as u64, (mean % 1_000_000_000) as u32),
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+        }
+    }
+}
+
+impl Serialize for Latency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Latency", 6)?;
+        let fields = [
+            ("min_us", self.min),
+            ("max_us", self.max),
+            ("mean_us", self.mean),
+            ("p50_us", self.p50),
+            ("p90_us", self.p90),
+            ("p99_us", self.p99),
+        ];
+        for (name, value) in fields.iter() {
+            state.serialize_field(name, &(value.as_micros() as u64))?;
+        }
+        state.end()
+    }
+}
+
+/// Statistics of all spans with one operation name
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OperationStats {
+    pub operation: String,
+    pub count: usize,
+    /// Spans tagged as failed, see `conventions::error`
+    pub errors: usize,
+    pub duration: Latency,
+    pub self_time: Latency,
+    total_self_time: Duration,
+}
+
+impl Serialize for OperationStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("OperationStats", 5)?;
+        state.serialize_field("operation", &self.operation)?;
+        state.serialize_field("count", &self.count)?;
+        state.serialize_field("errors", &self.errors)?;
+        state.serialize_field("duration", &self.duration)?;
+        state.serialize_field("self_time", &self.self_time)?;
+        state.end()
+    }
+}
+
+/// The spans of one operation
+#[derive(Debug, Default)]
+struct Samples {
+    count: usize,
+    errors: usize,
+    duration: Series,
+    self_time: Series,
+    /// State of the generator picking the samples to keep once there are too many
+    random: u64,
+}
+
+impl Samples {
+    /// Add a span's measures, keeping a uniform sample of at most
+    /// `max_samples` of them (reservoir sampling)
+    fn add(&mut self, duration: Duration, self_time: Duration, max_samples: usize) {
+        self.count += 1;
+        let slot = if self.count <= max_samples {
+            Some(self.count - 1)
+        } else {
+            let slot = (splitmix64(&mut self.random) % self.count as u64) as usize;
+            Some(slot).filter(|slot| *slot < max_samples)
+        };
+        self.duration.add(duration, slot);
+        self.self_time.add(self_time, slot);
+    }
+}
+
+/// A step of the splitmix64 generator, plenty for picking samples
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// One measure of an operation's spans
+#[derive(Debug, Default)]
+struct Series {
+    min: Option<Duration>,
+    max: Duration,
+    total: Duration,
+    samples: Vec<Duration>,
+}
+
+impl Series {
+    /// Add a value, storing it as the sample at `slot` if there is one
+    fn add(&mut self, value: Duration, slot: Option<usize>) {
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = self.max.max(value);
+        self.total += value;
+        match slot {
+            Some(slot) if slot < self.samples.len() => self.samples[slot] = value,
+            Some(_) => self.samples.push(value),
+            None => (),
+        }
+    }
+
+    /// Percentiles from the samples, the rest exact
+    fn latency(&self, count: usize) -> Latency {
+        if count == 0 {
+            return Default::default();
+        }
+        let mean = self.total.as_nanos() / count as u128;
+        Latency {
+            min: self.min.unwrap_or_default(),
+            max: self.max,
+            mean: Duration::new((mean / 1_000_000_000) as u64, (mean % 1_000_000_000) as u32),
+            ..Latency::from_samples(&mut self.samples.clone())
+        }
+    }
+}
+
+/// Time spent in the children of a span which hasn't been received yet
+#[derive(Debug)]
+struct Pending {
+    child_time: Duration,
+    last_finish: SystemTime,
+}
+
+/// A Reporter which aggregates per-operation latency statistics of the spans it receives.
+/// The self time of a span only accounts for children received before it,
+/// which is all of them unless children outlive their parent. The time of
+/// children whose parent isn't received within the parent timeout, measured on
+/// the spans' own finish times, is dropped, e.g. when the parent is in another
+/// process.
+#[derive(Debug)]
+pub struct StatsReporter {
+    span_rx: crossbeam_channel::Receiver<FinishedSpan>,
+    operations: HashMap<String, Samples>,
+    max_samples: usize,
+    parent_timeout: Duration,
+    /// Time spent in children received so far, by parent span id
+    pending: HashMap<u64, Pending>,
+    /// Pending parents by the time their last child finished, oldest first
+    expiry: BTreeSet<(SystemTime, u64)>,
+    /// The latest finish time received
+    latest: Option<SystemTime>,
+}
+
+impl StatsReporter {
+    /// Constructor
+    pub fn new(span_rx: crossbeam_channel::Receiver<FinishedSpan>) -> Self {
+        StatsReporter {
+            span_rx,
+            operations: HashMap::new(),
+            max_samples: DEFAULT_MAX_SAMPLES,
+            parent_timeout: DEFAULT_PARENT_TIMEOUT,
+            pending: HashMap::new(),
+            expiry: BTreeSet::new(),
+            latest: None,
+        }
+    }
+
+    /// Keep at most this many samples per operation for percentiles
+    pub fn with_max_samples(mut self, max_samples: usize) -> Self {
+        self.max_samples = max_samples;
+        self
+    }
+
+    /// Stop waiting for a parent this long after its last child finished
+    pub fn with_parent_timeout(mut self, parent_timeout: Duration) -> Self {
+        self.parent_timeout = parent_timeout;
+        self
+    }
+
+    /// Drain `span_rx` and add each span to the statistics
+    pub fn drain(&mut self) -> u32 {
+        let mut count = 0;
+        while let Ok(span) = self.span_rx.try_recv() {
+            count += 1;
+            self.record(&span);
+        }
+        count
+    }
+
+    /// Add a span received some other way to the statistics
+    pub fn record(&mut self, span: &FinishedSpan) {
+        let duration = span_duration(span);
+        let finish = span.finish_time();
+        for reference in span.references().iter().filter(|r| r.is_child_of()) {
+            let parent = reference.span().span_id();
+            let pending = self.pending.entry(parent).or_insert(Pending {
+                child_time: Duration::default(),
+                last_finish: finish,
+            });
+            self.expiry.remove(&(pending.last_finish, parent));
+            pending.child_time += duration;
+            pending.last_finish = pending.last_finish.max(finish);
+            self.expiry.insert((pending.last_finish, parent));
+        }
+        let span_id = span.context().state().span_id();
+        let child_time = match self.pending.remove(&span_id) {
+            Some(pending) => {
+                self.expiry.remove(&(pending.last_finish, span_id));
+                pending.child_time
+            }
+            None => Duration::default(),
+        };
+        self.latest = Some(self.latest.map_or(finish, |latest| latest.max(finish)));
+        self.expire();
+        let samples = self
+            .operations
+            .entry(span.operation_name().to_string())
+            .or_default();
+        let failed = span
+            .tags()
+            .iter()
+            .any(|t| t.name() == conventions::ERROR && *t.value() == TagValue::Boolean(true));
+        if failed {
+            samples.errors += 1;
+        }
+        // Concurrent children can add up to more than the parent
+        let self_time = duration.checked_sub(child_time).unwrap_or_default();
+        samples.add(duration, self_time, self.max_samples);
+    }
+
+    /// Forget the children of parents not received within the timeout
+    fn expire(&mut self) {
+        let latest = match self.latest {
+            Some(latest) => latest,
+            None => return,
+        };
+        while let Some(&(last_finish, parent)) = self.expiry.iter().next() {
+            match latest.duration_since(last_finish) {
+                Ok(waited) if waited > self.parent_timeout => {
+                    self.expiry.remove(&(last_finish, parent));
+                    self.pending.remove(&parent);
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Forget all statistics
+    pub fn clear(&mut self) {
+        let _ = self.drain();
+        self.operations.clear();
+        self.pending.clear();
+        self.expiry.clear();
+        self.latest = None;
+    }
+
+    /// Statistics per operation, in the given order
+    pub fn stats(&self, sort_by: SortBy) -> Vec<OperationStats> {
+        let mut stats: Vec<OperationStats> = self
+            .operations
+            .iter()
+            .map(|(operation, samples)| OperationStats {
+                operation: operation.clone(),
+                count: samples.count,
+                errors: samples.errors,
+                duration: samples.duration.latency(samples.count),
+                self_time: samples.self_time.latency(samples.count),
+                total_self_time: samples.self_time.total,
+            })
+            .collect();
+        stats.sort_by(|a, b| a.operation.cmp(&b.operation));
+        match sort_by {
+            SortBy::Name => (),
+            SortBy::Count => stats.sort_by_key(|s| Reverse(s.count)),
+            SortBy::Mean => stats.sort_by_key(|s| Reverse(s.duration.mean)),
+            SortBy::P99 => stats.sort_by_key(|s| Reverse(s.duration.p99)),
+            SortBy::TotalSelfTime => stats.sort_by_key(|s| Reverse(s.total_self_time)),
+        }
+        stats
+    }
+
+    /// Print the statistics as a table to console
+    pub fn print(&self, sort_by: SortBy) {
+        let stdout = io::stdout();
+        self.render(&mut stdout.lock(), sort_by)
+            .expect("Failed to print stats to stdout");
+    }
+
+    /// Write the statistics as a table to any writer
+    pub fn render<W: Write>(&self, writer: &mut W, sort_by: SortBy) -> io::Result<()> {
+        let stats = self.stats(sort_by);
+        let width = stats
+            .iter()
+            .map(|s| s.operation.len())
+            .chain(std::iter::once("operation".len()))
+            .max()
+            .unwrap_or_default();
+        let columns = ["min", "mean", "p50", "p90", "p99", "max"];
+        let mut header = format!(
+            "{:<width$} {:>7} {:>7} |",
+            "operation",
+            "count",
+            "errors",
+            width = width
+        );
+        for prefix in ["", "self "].iter() {
+            for column in columns.iter() {
+                header.push_str(&format!(" {:>14}", format!("{}{}", prefix, column)));
+            }
+            header.push_str(" |");
+        }
+        writeln!(writer, "{}", header.trim_end_matches(" |"))?;
+        for s in stats {
+            let mut row = format!(
+                "{:<width$} {:>7} {:>7} |",
+                s.operation,
+                s.count,
+                s.errors,
+                width = width
+            );
+            for latency in [s.duration, s.self_time].iter() {
+                for d in [
+                    latency.min,
+                    latency.mean,
+                    latency.p50,
+                    latency.p90,
+                    latency.p99,
+                    latency.max,
+                ]
+                .iter()
+                {
+                    row.push_str(&format!(" {:>14}", format_duration(*d)));
+                }
+                row.push_str(" |");
+            }
+            writeln!(writer, "{}", row.trim_end_matches(" |"))?;
+        }
+        Ok(())
+    }
+
+    /// Render the statistics as a table into a String
+    pub fn render_to_string(&self, sort_by: SortBy) -> String {
+        let mut out = Vec::new();
+        self.render(&mut out, sort_by)
+            .expect("Writing to a Vec can't fail");
+        String::from_utf8(out).expect("Rendered stats are always UTF-8")
+    }
+
+    /// Write the statistics as a JSON array of `OperationStats`
+    pub fn write_json<W: Write>(&self, writer: &mut W, sort_by: SortBy) -> io::Result<()> {
+        serde_json::to_writer_pretty(writer, &self.stats(sort_by)).map_err(io::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_latency() {
+        let mut samples: Vec<_> = (1..=100).rev().map(Duration::from_millis).collect();
+        let latency = Latency::from_samples(&mut samples);
+        assert_eq!(latency.min, Duration::from_millis(1));
+        assert_eq!(latency.max, Duration::from_millis(100));
+        assert_eq!(latency.mean, Duration::from_micros(50_500));
+        assert_eq!(latency.p50, Duration::from_millis(50));
+        assert_eq!(latency.p90, Duration::from_millis(90));
+        assert_eq!(latency.p99, Duration::from_millis(99));
+        let single = Latency::from_samples(&mut [Duration::from_millis(3)]);
+        assert_eq!(single.p50, Duration::from_millis(3));
+        assert_eq!(single.p99, Duration::from_millis(3));
+    }
+
+    #[test]
+    fn test_stats() {
+        let (tracer, mut reporter) = new_tracer_with_stats_reporter();
+        let t0 = SystemTime::now();
+        let ms = Duration::from_millis;
+        for i in 0..2 {
+            let mut call = tracer.span("call").start_time(t0).start();
+            call.set_finish_time(|| t0 + ms(10));
+            let mut query = call.child("query", |o| o.start_time(t0 + ms(1)).start());
+            query.set_finish_time(|| t0 + ms(3 + 2 * i));
+            if i == 1 {
+                query.set_tag(conventions::error);
+            }
+        }
+        assert_eq!(reporter.drain(), 4);
+        let stats = reporter.stats(SortBy::TotalSelfTime);
+        assert_eq!(stats.len(), 2);
+        let (call, query) = (&stats[0], &stats[1]);
+        assert_eq!(
+            (call.operation.as_str(), call.count, call.errors),
+            ("call", 2, 0)
+        );
+        assert_eq!(call.duration.mean, ms(10));
+        assert_eq!(call.self_time.min, ms(6));
+        assert_eq!(call.self_time.max, ms(8));
+        assert_eq!((query.operation.as_str(), query.errors), ("query", 1));
+        assert_eq!(query.duration.mean, ms(3));
+        assert_eq!(query.self_time, query.duration);
+
+        let table = reporter.render_to_string(SortBy::Name);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("operation   count  errors |            min           mean"));
+        assert!(lines[0].ends_with("self p99       self max"));
+        assert!(lines[1].starts_with("call            2       0 |       10.000ms       10.000ms"));
+        assert!(lines[2].starts_with("query           2       1 |        2.000ms        3.000ms"));
+
+        let mut json = Vec::new();
+        reporter.write_json(&mut json, SortBy::Name).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json[1]["operation"], "query");
+        assert_eq!(json[1]["duration"]["p90_us"], 4000);
+        assert_eq!(json[0]["self_time"]["mean_us"], 7000);
+    }
+
+    #[test]
+    fn test_bounded_samples() {
+        let (span_tx, span_rx) = crossbeam_channel::unbounded();
+        let tracer = Tracer::with_sender(AllSampler, span_tx);
+        let mut reporter = StatsReporter::new(span_rx).with_max_samples(100);
+        let t0 = SystemTime::now();
+        for i in 1..=1000 {
+            let mut span = tracer.span("op").start_time(t0).start();
+            span.set_finish_time(|| t0 + Duration::from_millis(i));
+        }
+        assert_eq!(reporter.drain(), 1000);
+        assert_eq!(reporter.operations["op"].duration.samples.len(), 100);
+        let stats = reporter.stats(SortBy::Name);
+        let latency = stats[0].duration;
+        assert_eq!(stats[0].count, 1000);
+        assert_eq!(latency.min, Duration::from_millis(1));
+        assert_eq!(latency.max, Duration::from_millis(1000));
+        assert_eq!(latency.mean, Duration::from_micros(500_500));
+        // A uniform sample puts the median near the middle
+        assert!(latency.p50 > Duration::from_millis(300));
+        assert!(latency.p50 < Duration::from_millis(700));
+    }
+
+    #[test]
+    fn test_parent_timeout() {
+        let (tracer, reporter) = new_tracer_with_stats_reporter();
+        let mut reporter = reporter.with_parent_timeout(Duration::from_secs(60));
+        let t0 = SystemTime::now();
+        let ms = Duration::from_millis;
+        // A parent which is never received, e.g. in another process
+        let lost = tracer.span("lost").start();
+        {
+            let mut child = lost.child("child", |o| o.start_time(t0).start());
+            child.set_finish_time(|| t0 + ms(1));
+        }
+        let mut call = tracer.span("call").start_time(t0).start();
+        {
+            let mut child = call.child("child", |o| o.start_time(t0).start());
+            child.set_finish_time(|| t0 + ms(1));
+        }
+        reporter.drain();
+        assert_eq!(reporter.pending.len(), 2);
+
+        // Long after the lost parent's child, the other parent still counts its child
+        call.set_finish_time(|| t0 + Duration::from_secs(120));
+        drop(call);
+        reporter.drain();
+        assert!(reporter.pending.is_empty());
+        assert!(reporter.expiry.is_empty());
+        let call = &reporter.stats(SortBy::Name)[0];
+        assert_eq!(call.self_time.max, Duration::from_secs(120) - ms(1));
+    }
+}
@@ -1,9 +1,199 @@
 use rustracing::tag::Tag;
+use serde::Serialize;
+use std::borrow::Cow;
+
+/// Appended to tag values which were cut short by one of the `_capped` functions
+pub const TRUNCATION_MARKER: &str = "…";
 
 pub fn debug_tag<N, D>(key: N, val: D) -> Tag
 where
-    N: Into<std::borrow::Cow<'static, str>>,
+    N: Into<Cow<'static, str>>,
     D: std::fmt::Debug,
 {
     Tag::new(key, format!("{:?}", val))
 }
+
+/// Tag with the `Display` form of a value
+pub fn display_tag<N, D>(key: N, val: D) -> Tag
+where
+    N: Into<Cow<'static, str>>,
+    D: std::fmt::Display,
+{
+    Tag::new(key, val.to_string())
+}
+
+/// Tag with a value serialized as JSON. If serialization fails, the tag
+/// value describes the error instead.
+pub fn json_tag<N, S>(key: N, val: &S) -> Tag
+where
+    N: Into<Cow<'static, str>>,
+    S: Serialize + ?Sized,
+{
+    Tag::new(key, to_json(val))
+}
+
+/// Tag with a byte buffer as lowercase hex
+pub fn hex_tag<N, B>(key: N, bytes: B) -> Tag
+where
+    N: Into<Cow<'static, str>>,
+    B: AsRef<[u8]>,
+{
+    Tag::new(key, to_hex(bytes.as_ref()))
+}
+
+/// Tag with a byte buffer in standard, padded base64
+pub fn base64_tag<N, B>(key: N, bytes: B) -> Tag
+where
+    N: Into<Cow<'static, str>>,
+    B: AsRef<[u8]>,
+{
+    Tag::new(key, to_base64(bytes.as_ref()))
+}
+
+/// Like `debug_tag`, but the value is cut to at most `max_len` bytes
+pub fn debug_tag_capped<N, D>(key: N, val: D, max_len: usize) -> Tag
+where
+    N: Into<Cow<'static, str>>,
+    D: std::fmt::Debug,
+{
+    Tag::new(key, cap(format!("{:?}", val), max_len))
+}
+
+/// Like `display_tag`, but the value is cut to at most `max_len` bytes
+pub fn display_tag_capped<N, D>(key: N, val: D, max_len: usize) -> Tag
+where
+    N: Into<Cow<'static, str>>,
+    D: std::fmt::Display,
+{
+    Tag::new(key, cap(val.to_string(), max_len))
+}
+
+/// Like `json_tag`, but the value is cut to at most `max_len` bytes
+pub fn json_tag_capped<N, S>(key: N, val: &S, max_len: usize) -> Tag
+where
+    N: Into<Cow<'static, str>>,
+    S: Serialize + ?Sized,
+{
+    Tag::new(key, cap(to_json(val), max_len))
+}
+
+/// Like `hex_tag`, but the value is cut to at most `max_len` bytes
+pub fn hex_tag_capped<N, B>(key: N, bytes: B, max_len: usize) -> Tag
+where
+    N: Into<Cow<'static, str>>,
+    B: AsRef<[u8]>,
+{
+    // Only encode as much as can possibly be shown, plus a little to trigger truncation
+    let bytes = bytes.as_ref();
+    let shown = &bytes[..bytes.len().min(max_len / 2 + 1)];
+    Tag::new(key, cap(to_hex(shown), max_len))
+}
+
+/// Like `base64_tag`, but the value is cut to at most `max_len` bytes
+pub fn base64_tag_capped<N, B>(key: N, bytes: B, max_len: usize) -> Tag
+where
+    N: Into<Cow<'static, str>>,
+    B: AsRef<[u8]>,
+{
+    // Only encode as much as can possibly be shown, plus a little to trigger truncation
+    let bytes = bytes.as_ref();
+    let shown = &bytes[..bytes.len().min((max_len / 4 + 1) * 3)];
+    Tag::new(key, cap(to_base64(shown), max_len))
+}
+
+fn to_json<S: Serialize + ?Sized>(val: &S) -> String {
+    serde_json::to_string(val).unwrap_or_else(|e| format!("<json error: {}>", e))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        out.push(DIGITS[(b >> 4) as usize] as char);
+        out.push(DIGITS[(b & 0xf) as usize] as char);
+    }
+    out
+}
+
+fn to_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len() / 3 * 4 + 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (u32::from(*b) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Cut `val` to at most `max_len` bytes including the marker, on a char boundary.
+/// If not even the marker fits, the value is cut without one.
+fn cap(val: String, max_len: usize) -> String {
+    if val.len() <= max_len {
+        return val;
+    }
+    let marker = if max_len < TRUNCATION_MARKER.len() {
+        ""
+    } else {
+        TRUNCATION_MARKER
+    };
+    let mut end = max_len - marker.len();
+    while !val.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &val[..end], marker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustracing::tag::TagValue;
+
+    fn value(tag: Tag) -> String {
+        match tag.value() {
+            TagValue::String(s) => s.to_string(),
+            other => panic!("not a string tag: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_encodings() {
+        assert_eq!(value(display_tag("k", 1.5)), "1.5");
+        assert_eq!(value(json_tag("k", &vec!["a", "b"])), r#"["a","b"]"#);
+        assert_eq!(value(hex_tag("k", [0u8, 0xab, 0x10])), "00ab10");
+        assert_eq!(value(base64_tag("k", b"")), "");
+        assert_eq!(value(base64_tag("k", b"f")), "Zg==");
+        assert_eq!(value(base64_tag("k", b"fo")), "Zm8=");
+        assert_eq!(value(base64_tag("k", b"foobar")), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_capped() {
+        assert_eq!(value(display_tag_capped("k", "short", 10)), "short");
+        assert_eq!(value(display_tag_capped("k", "abcdefghij", 8)), "abcde…");
+        // Never splits a multibyte char
+        assert_eq!(value(display_tag_capped("k", "ééééé", 7)), "éé…");
+        assert_eq!(value(debug_tag_capped("k", "abcdefghij", 8)), "\"abcd…");
+        assert_eq!(value(json_tag_capped("k", &[1, 2, 3, 4, 5], 8)), "[1,2,…");
+        assert_eq!(
+            value(hex_tag_capped("k", [0xffu8; 8], 16)),
+            "ffffffffffffffff"
+        );
+        assert_eq!(value(hex_tag_capped("k", [0xffu8; 100], 10)), "fffffff…");
+        assert_eq!(value(base64_tag_capped("k", b"foobar", 8)), "Zm9vYmFy");
+        assert_eq!(value(base64_tag_capped("k", [0u8; 100], 10)), "AAAAAAA…");
+        // Too short for the marker
+        assert_eq!(value(display_tag_capped("k", "abc", 0)), "");
+        assert_eq!(value(display_tag_capped("k", "abc", 1)), "a");
+        assert_eq!(value(display_tag_capped("k", "abc", 2)), "ab");
+        assert_eq!(value(display_tag_capped("k", "éé", 1)), "");
+    }
+}
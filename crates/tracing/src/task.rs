@@ -0,0 +1,164 @@
+//! Instrumentation for futures. An instrumented future owns a `TaskStack`, which
+//! is swapped in place of the thread's span stack every time the future is polled.
+//! That way the stack functions (`with_top`, `push_span_with`, `top_follower`, and
+//! the autotrace macros built on them) see the spans of the task being polled,
+//! even when an executor interleaves many tasks on one thread.
+//! Outside of an instrumented future they keep using the thread's stack.
+
+use crate::stack::{with_top, TaskStack};
+use crate::Span;
+use std::borrow::Cow;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A future running with its own span stack
+pub struct Instrumented<F> {
+    /// Only taken on drop
+    inner: Option<Pin<Box<F>>>,
+    stack: TaskStack,
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Both fields are Unpin, so we can get at them without any pin projection
+        let this = self.get_mut();
+        let inner = this.inner.as_mut().expect("inner only taken on drop");
+        this.stack.enter(|| inner.as_mut().poll(cx))
+    }
+}
+
+impl<F> Drop for Instrumented<F> {
+    fn drop(&mut self) {
+        // A future dropped while pending, e.g. a cancelled branch of a select,
+        // may hold guards into the task stack, which must not pop the thread's
+        let inner = self.inner.take();
+        self.stack.enter(|| drop(inner));
+    }
+}
+
+/// Extension trait to attach a span stack to any future
+pub trait Instrument: Future + Sized {
+    /// Run this future with `span` at the bottom of its own span stack
+    fn instrument(self, span: Span) -> Instrumented<Self> {
+        Instrumented {
+            inner: Some(Box::pin(self)),
+            stack: TaskStack::new(span),
+        }
+    }
+
+    /// Run this future under a new child of the span currently on top of the
+    /// stack. If the stack is empty the future runs under a noop span.
+    fn in_current_span<N: Into<Cow<'static, str>>>(self, name: N) -> Instrumented<Self> {
+        let span = with_top(|top| top.child(name)).unwrap_or_else(Span::noop);
+        self.instrument(span)
+    }
+}
+
+impl<F: Future> Instrument for F {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{push_span, push_span_with, tracer_console};
+    use std::sync::Arc;
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+
+    /// Yields once before completing, to interleave polls of several tasks
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Never completes
+    struct Never;
+
+    impl Future for Never {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+            Poll::Pending
+        }
+    }
+
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+    }
+
+    fn top_span_id() -> Option<u64> {
+        with_top(|top| top.context().map(|c| c.0.state().span_id())).flatten()
+    }
+
+    #[test]
+    fn test_tasks_interleaved_on_one_thread() {
+        let (tracer, mut reporter) = tracer_console::new_tracer_with_console_reporter();
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let task = |name: &'static str| {
+            let seen = seen.clone();
+            async move {
+                let _g = push_span_with(|s| s.child(name));
+                let before = top_span_id();
+                YieldOnce(false).await;
+                let after = top_span_id();
+                seen.lock().unwrap().push(before == after);
+            }
+        };
+        let _thread_root = push_span(tracer.span("thread").start().into());
+        let thread_top = top_span_id();
+        let mut a = Box::pin(task("a").instrument(tracer.span("task a").start().into()));
+        let mut b = Box::pin(task("b").in_current_span("task b"));
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        // Poll both tasks to their first yield, then to completion
+        for _ in 0..2 {
+            let _ = a.as_mut().poll(&mut cx);
+            let _ = b.as_mut().poll(&mut cx);
+            assert_eq!(top_span_id(), thread_top);
+        }
+        drop((a, b));
+        assert_eq!(*seen.lock().unwrap(), vec![true, true]);
+        assert_eq!(reporter.drain(), 4);
+    }
+
+    #[test]
+    fn test_drop_pending_task() {
+        let (tracer, mut reporter) = tracer_console::new_tracer_with_console_reporter();
+        let mut task = Box::pin(
+            async {
+                let _g = push_span_with(|s| s.child("held across await"));
+                Never.await;
+            }
+            .instrument(tracer.span("task").start().into()),
+        );
+        let waker = noop_waker();
+        assert!(task
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+        let _thread_root = push_span(tracer.span("thread").start().into());
+        let _thread_child = push_span_with(|s| s.child("thread child"));
+        let thread_top = top_span_id();
+        drop(task);
+        assert_eq!(top_span_id(), thread_top);
+        // Only the task's spans have finished
+        assert_eq!(reporter.drain(), 2);
+    }
+}
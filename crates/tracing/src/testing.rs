@@ -0,0 +1,393 @@
+//! A harness for testing instrumentation. `capture_trace` runs a closure under
+//! a capturing tracer with a root span on the stack, and returns the spans it
+//! produced as a `CapturedTrace`, which has assertions on the shape of the
+//! trace and on the tags and logs of its spans.
+//!
+//! Spans are named by patterns where `*` matches any run of characters, so
+//! tests needn't spell out e.g. the source locations in autotrace span names.
+//! Failed assertions panic with the captured tree, or a line diff against the
+//! expected shape.
+//!
+//! ```
+//! use holochain_tracing::{push_span_with, testing::capture_trace};
+//!
+//! let (_, trace) = capture_trace(|| {
+//!     let a = push_span_with(|s| s.child("a"));
+//!     drop(a);
+//!     let _b = push_span_with(|s| s.follower("b"));
+//! });
+//! trace.assert_shape(
+//!     "
+//!     root
+//!       a
+//!     ~ b
+//!     ",
+//! );
+//! trace.assert_children("root", &["a"]);
+//! trace.assert_follows("b", "root");
+//! ```
+
+use crate::query::{glob_match, SpanGraph};
+use crate::{push_span, AllSampler, FinishedSpan, Tracer};
+use rustracing::tag::TagValue;
+use std::sync::Arc;
+
+/// Name of the root span `capture_trace` puts on the stack
+pub const ROOT_NAME: &str = "root";
+
+/// Run `f` with a root span named `root` on the span stack, and capture every
+/// span finished by the time `f` returns, including the root itself.
+/// Spans sent from other threads are only captured if those threads finished them.
+pub fn capture_trace<R, F: FnOnce() -> R>(f: F) -> (R, CapturedTrace) {
+    let (span_tx, span_rx) = crossbeam_channel::unbounded();
+    let tracer = Tracer::with_sender(AllSampler, span_tx);
+    let (result, root_id) = {
+        let root = tracer.span(ROOT_NAME).start();
+        let root_id = root.context().map(|c| c.state().span_id());
+        let _guard = push_span(root.into());
+        (f(), root_id)
+    };
+    let graph = SpanGraph::new(span_rx.try_iter().map(Arc::new));
+    let root_id = root_id.expect("Spans from an AllSampler tracer have a context");
+    (result, CapturedTrace { graph, root_id })
+}
+
+/// The spans captured by `capture_trace`
+#[derive(Debug)]
+pub struct CapturedTrace {
+    graph: SpanGraph,
+    root_id: u64,
+}
+
+impl CapturedTrace {
+    /// The captured spans, to query or walk directly
+    pub fn graph(&self) -> &SpanGraph {
+        &self.graph
+    }
+
+    /// The root span put on the stack by `capture_trace`
+    pub fn root(&self) -> &FinishedSpan {
+        self.graph
+            .get(self.root_id)
+            .expect("The root span is always captured")
+    }
+
+    /// All spans matching `pattern`, ordered by start time
+    pub fn find_all(&self, pattern: &str) -> Vec<&FinishedSpan> {
+        self.graph
+            .spans()
+            .into_iter()
+            .filter(|s| glob_match(pattern, s.operation_name()))
+            .collect()
+    }
+
+    /// The one span matching `pattern`. Panics if there are none or several.
+    pub fn find(&self, pattern: &str) -> &FinishedSpan {
+        match self.find_all(pattern).as_slice() {
+            [span] => span,
+            [] => self.fail(format!("no span matches {:?}", pattern)),
+            spans => self.fail(format!(
+                "{} spans match {:?}, expected exactly one",
+                spans.len(),
+                pattern
+            )),
+        }
+    }
+
+    /// The captured spans as an indented tree of operation names, the form
+    /// `assert_shape` expects. Children are indented by two spaces under their
+    /// parent, and followers are marked with `~ ` at the level of the span they
+    /// follow from. Spans which don't reference a captured span are listed last.
+    pub fn tree(&self) -> String {
+        let mut lines = Vec::new();
+        for root in self.graph.roots() {
+            self.tree_lines(root, 0, "", &mut lines);
+        }
+        lines.join("\n")
+    }
+
+    /// Assert the whole trace has the shape `expected`, in the form of `tree`.
+    /// Each line is a pattern. Blank lines and the indentation common to all
+    /// lines are ignored, so `expected` can be an indented multiline string.
+    pub fn assert_shape(&self, expected: &str) {
+        let expected = dedent(expected);
+        let actual = self.tree();
+        let actual: Vec<&str> = actual.lines().collect();
+        let matches = actual.len() == expected.len()
+            && expected
+                .iter()
+                .zip(actual.iter())
+                .all(|(e, a)| line_matches(e, a));
+        if !matches {
+            panic!(
+                "trace shape differs (- expected, + actual):\n{}",
+                diff(&expected, &actual)
+            );
+        }
+    }
+
+    /// Assert the children of the span matching `parent` match `expected`, in start order
+    pub fn assert_children(&self, parent: &str, expected: &[&str]) {
+        let parent_span = self.find(parent);
+        let children: Vec<&str> = self
+            .graph
+            .children(span_id(parent_span))
+            .iter()
+            .map(|s| s.operation_name())
+            .collect();
+        let matches = children.len() == expected.len()
+            && expected
+                .iter()
+                .zip(children.iter())
+                .all(|(e, a)| glob_match(e, a));
+        if !matches {
+            self.fail(format!(
+                "children of {:?} differ (- expected, + actual):\n{}",
+                parent,
+                diff(expected, &children)
+            ));
+        }
+    }
+
+    /// Assert the span matching `follower` follows from the span matching `leader`
+    pub fn assert_follows(&self, follower: &str, leader: &str) {
+        let follower_span = self.find(follower);
+        let leader_span = self.find(leader);
+        let followed = self.graph.followed(span_id(follower_span));
+        if followed.map(span_id) != Some(span_id(leader_span)) {
+            self.fail(format!(
+                "{:?} does not follow from {:?}, it follows from {:?}",
+                follower,
+                leader,
+                followed.map(|s| s.operation_name())
+            ));
+        }
+    }
+
+    /// Assert the span matching `span` has a tag `key` equal to `value`
+    pub fn assert_tag<V: Into<TagValue>>(&self, span: &str, key: &str, value: V) {
+        let value = value.into();
+        let found = self.find(span);
+        if !found
+            .tags()
+            .iter()
+            .any(|t| t.name() == key && *t.value() == value)
+        {
+            let tags: Vec<String> = found
+                .tags()
+                .iter()
+                .map(|t| format!("{} = {:?}", t.name(), t.value()))
+                .collect();
+            self.fail(format!(
+                "{:?} has no tag {} = {:?}, its tags are [{}]",
+                span,
+                key,
+                value,
+                tags.join(", ")
+            ));
+        }
+    }
+
+    /// Assert some log field of the span matching `span` contains `text`
+    pub fn assert_log(&self, span: &str, text: &str) {
+        let found = self.find(span);
+        let fields: Vec<String> = found
+            .logs()
+            .iter()
+            .flat_map(|l| l.fields())
+            .map(|f| format!("{}: {}", f.name(), f.value()))
+            .collect();
+        if !fields.iter().any(|f| f.contains(text)) {
+            self.fail(format!(
+                "no log of {:?} contains {:?}, its logs are [{}]",
+                span,
+                text,
+                fields.join(", ")
+            ));
+        }
+    }
+
+    fn tree_lines(&self, span: &FinishedSpan, depth: usize, marker: &str, lines: &mut Vec<String>) {
+        // Bounded by the number of spans, in case the references form a cycle
+        if lines.len() > self.graph.len() {
+            return;
+        }
+        lines.push(format!(
+            "{}{}{}",
+            "  ".repeat(depth),
+            marker,
+            span.operation_name()
+        ));
+        let id = span_id(span);
+        for child in self.graph.children(id) {
+            self.tree_lines(child, depth + 1, "", lines);
+        }
+        for follower in self.graph.followers(id) {
+            self.tree_lines(follower, depth, "~ ", lines);
+        }
+    }
+
+    fn fail(&self, message: String) -> ! {
+        panic!("{}\ncaptured trace:\n{}", message, self.tree())
+    }
+}
+
+fn span_id(span: &FinishedSpan) -> u64 {
+    span.context().state().span_id()
+}
+
+/// Indentation must match exactly, the rest of the line is a pattern
+fn line_matches(expected: &str, actual: &str) -> bool {
+    let indent = |s: &str| s.len() - s.trim_start().len();
+    indent(expected) == indent(actual) && glob_match(expected.trim_start(), actual.trim_start())
+}
+
+fn dedent(text: &str) -> Vec<&str> {
+    let lines: Vec<&str> = text
+        .lines()
+        .map(|l| l.trim_end())
+        .filter(|l| !l.is_empty())
+        .collect();
+    let indent = lines
+        .iter()
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    lines.into_iter().map(|l| &l[indent..]).collect()
+}
+
+/// A line diff, matching lines by pattern, marking lines only in `expected`
+/// with `-` and lines only in `actual` with `+`
+fn diff<E: AsRef<str>, A: AsRef<str>>(expected: &[E], actual: &[A]) -> String {
+    let same = |i: usize, j: usize| line_matches(expected[i].as_ref(), actual[j].as_ref());
+    // Longest common subsequence table, from the ends of both lists
+    let (n, m) = (expected.len(), actual.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if same(i, j) {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && same(i, j) {
+            out.push(format!("  {}", actual[j].as_ref()));
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            out.push(format!("+ {}", actual[j].as_ref()));
+            j += 1;
+        } else {
+            out.push(format!("- {}", expected[i].as_ref()));
+            i += 1;
+        }
+    }
+    out.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{push_span_with, with_top};
+    use std::panic;
+
+    fn sample() -> CapturedTrace {
+        capture_trace(|| {
+            {
+                let _a = push_span_with(|s| s.child("a"));
+                let _c = push_span_with(|s| s.child("c in file.rs:1"));
+                with_top(|s| {
+                    s.set_tag(|| crate::Tag::new("n", 1));
+                    s.event("hello there");
+                });
+            }
+            let _b = push_span_with(|s| s.child("b"));
+            let _d = push_span_with(|s| s.follower("d"));
+        })
+        .1
+    }
+
+    fn panic_message<F: FnOnce() + panic::UnwindSafe>(f: F) -> String {
+        let err = panic::catch_unwind(f).expect_err("assertion should have failed");
+        err.downcast_ref::<String>().cloned().unwrap_or_default()
+    }
+
+    #[test]
+    fn test_assertions() {
+        let trace = sample();
+        assert_eq!(trace.root().operation_name(), ROOT_NAME);
+        assert_eq!(trace.tree(), "root\n  a\n    c in file.rs:1\n  b\n  ~ d");
+        trace.assert_shape(
+            "
+            root
+              a
+                c in *
+              b
+              ~ d
+            ",
+        );
+        trace.assert_children("root", &["a", "b"]);
+        trace.assert_children("c*", &[]);
+        trace.assert_follows("d", "b");
+        trace.assert_tag("c*", "n", 1);
+        trace.assert_log("c*", "hello");
+    }
+
+    /// The shape the autotrace macros give nested calls, each call pushing
+    /// a child of the span on top of the stack
+    #[test]
+    fn test_nested_calls() {
+        fn call(name: &'static str, rest: &[&'static str], x: u32) -> u32 {
+            let _g = push_span_with(|s| s.child(name));
+            match rest.split_first() {
+                Some((next, rest)) => call(next, rest, x + 10),
+                None => x,
+            }
+        }
+        let (x, trace) = capture_trace(|| call("a", &["b", "c"], 0));
+        assert_eq!(x, 20);
+        assert_eq!(trace.graph().len(), 4);
+        trace.assert_shape(
+            "
+            root
+              a
+                b
+                  c
+            ",
+        );
+        // Innermost first, each no later than its parent
+        let finish = |name| trace.find(name).finish_time();
+        assert!(finish("c") <= finish("b"));
+        assert!(finish("b") <= finish("a"));
+        assert!(finish("a") <= finish("root"));
+    }
+
+    #[test]
+    fn test_failure_messages() {
+        let trace = sample();
+        let message = panic_message(|| trace.assert_shape("root\n  b\n  a\n    c*\n  ~ d"));
+        assert_eq!(
+            message,
+            "trace shape differs (- expected, + actual):\n  root\n-   b\n    a\n      c in file.rs:1\n+   b\n    ~ d"
+        );
+        let message = panic_message(|| trace.assert_children("root", &["a", "x"]));
+        assert!(message.starts_with(
+            "children of \"root\" differ (- expected, + actual):\n  a\n+ b\n- x\ncaptured trace:\n"
+        ));
+        let message = panic_message(|| trace.assert_tag("a", "n", 1));
+        assert!(message.starts_with("\"a\" has no tag n = Integer(1), its tags are []"));
+        let message = panic_message(|| trace.assert_follows("d", "a"));
+        assert!(
+            message.starts_with("\"d\" does not follow from \"a\", it follows from Some(\"b\")")
+        );
+        assert!(panic_message(|| {
+            trace.find("*");
+        })
+        .starts_with("5 spans match \"*\""));
+    }
+}
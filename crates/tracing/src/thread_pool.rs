@@ -0,0 +1,161 @@
+//! A small worker pool which carries the span stack across to its workers.
+//! Since the span stack is thread-local, jobs run on a pool would otherwise
+//! lose track of the trace they were submitted from. Each job captures a
+//! `SpanSnapshot` when it is submitted, and the worker restores it before
+//! running the job, so spans created inside the job join the submitter's trace.
+
+use crate::stack::{capture, SpanSnapshot};
+pub use crate::utils::Relation;
+use crossbeam_channel as cb;
+use std::borrow::Cow;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Wrap a job so that it runs under a span restored from the current stack.
+/// Useful for submitting to pools other than `TracedPool`, e.g. rayon.
+pub fn traced<N, F, R>(name: N, relation: Relation, f: F) -> impl FnOnce() -> R + Send
+where
+    N: Into<Cow<'static, str>> + Send,
+    F: FnOnce() -> R + Send,
+{
+    let snapshot = capture();
+    move || run_traced(&snapshot, name, relation, f)
+}
+
+fn run_traced<N, F, R>(snapshot: &SpanSnapshot, name: N, relation: Relation, f: F) -> R
+where
+    N: Into<Cow<'static, str>>,
+    F: FnOnce() -> R,
+{
+    let _guard = match relation {
+        Relation::ChildOf => snapshot.child(name),
+        Relation::FollowsFrom => snapshot.follower(name),
+    };
+    f()
+}
+
+/// A fixed size pool of worker threads fed by a crossbeam channel.
+/// Dropping the pool waits for all submitted jobs to finish.
+pub struct TracedPool {
+    job_tx: Option<cb::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+    relation: Relation,
+}
+
+impl TracedPool {
+    /// Constructor. Jobs are traced as followers of the submitting span.
+    pub fn new(size: usize) -> Self {
+        let (job_tx, job_rx) = cb::unbounded::<Job>();
+        let workers = (0..size.max(1))
+            .map(|i| {
+                let job_rx = job_rx.clone();
+                thread::Builder::new()
+                    .name(format!("traced-pool-{}", i))
+                    .spawn(move || {
+                        for job in job_rx {
+                            run_job(job);
+                        }
+                    })
+                    .expect("Failed to spawn pool worker")
+            })
+            .collect();
+        TracedPool {
+            job_tx: Some(job_tx),
+            workers,
+            relation: Relation::FollowsFrom,
+        }
+    }
+
+    /// How jobs are traced in relation to the submitting span
+    pub fn with_relation(mut self, relation: Relation) -> Self {
+        self.relation = relation;
+        self
+    }
+
+    /// Number of worker threads
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Submit a job, which will run under a new span named `name` restored
+    /// from the top of the current span stack.
+    pub fn execute<N, F>(&self, name: N, f: F)
+    where
+        N: Into<Cow<'static, str>> + Send + 'static,
+        F: FnOnce() + Send + 'static,
+    {
+        let job = traced(name, self.relation, f);
+        self.job_tx
+            .as_ref()
+            .expect("job_tx only taken on drop")
+            .send(Box::new(job))
+            .expect("Pool workers hung up");
+    }
+}
+
+/// Run a job, logging rather than propagating its panic so the worker survives
+fn run_job(job: Job) {
+    if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(|s| s.as_str()))
+            .unwrap_or("<non-string panic payload>");
+        error!(
+            "Job panicked on {}: {}",
+            thread::current().name().unwrap_or("pool worker"),
+            message
+        );
+    }
+}
+
+impl Drop for TracedPool {
+    fn drop(&mut self) {
+        // Hanging up the channel lets workers finish the queue and exit
+        self.job_tx.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{push_span, tracer_console, with_top};
+
+    #[test]
+    fn test_pool_propagates_trace() {
+        let (tracer, mut reporter) = tracer_console::new_tracer_with_console_reporter();
+        let (result_tx, result_rx) = cb::unbounded();
+        let root_trace = {
+            let _g = push_span(tracer.span("submitter").start().into());
+            let pool = TracedPool::new(2);
+            for _ in 0..4 {
+                let result_tx = result_tx.clone();
+                pool.execute("job", move || {
+                    let trace_id = with_top(|top| top.context().map(|c| c.0.state().trace_id()));
+                    result_tx.send(trace_id.flatten()).unwrap();
+                });
+            }
+            with_top(|top| top.context().unwrap().0.state().trace_id()).unwrap()
+        };
+        drop(result_tx);
+        let trace_ids: Vec<_> = result_rx.iter().collect();
+        assert_eq!(trace_ids.len(), 4);
+        assert!(trace_ids.iter().all(|id| *id == Some(root_trace)));
+        assert_eq!(reporter.drain(), 5);
+    }
+
+    #[test]
+    fn test_pool_survives_panicking_job() {
+        let (result_tx, result_rx) = cb::unbounded();
+        let pool = TracedPool::new(1);
+        pool.execute("fails", || panic!("job failed"));
+        pool.execute("succeeds", move || result_tx.send(()).unwrap());
+        drop(pool);
+        assert_eq!(result_rx.try_iter().count(), 1);
+    }
+}
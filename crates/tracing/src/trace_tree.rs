@@ -0,0 +1,279 @@
+//! The shape of a set of spans as a forest, computed in one pass so that
+//! rendering is linear in the number of spans. Each span is placed once: under
+//! the first referenced span which is present, as a child for `ChildOf` and as
+//! a follower for `FollowsFrom`, with `ChildOf` taking precedence. Spans
+//! whose references are all missing are roots, and reference cycles are broken
+//! at their earliest span, so every span is placed exactly once.
+//! Nothing here recurses, so arbitrarily deep traces are fine too.
+
+use crate::FinishedSpan;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// How a node hangs off its parent node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Placement {
+    Root,
+    Child,
+    Follower,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct TreeNode {
+    pub span_id: u64,
+    pub start_time: SystemTime,
+    pub placement: Placement,
+    pub parent: Option<usize>,
+    /// Children, then followers, each in start order
+    pub children: Vec<usize>,
+    pub followers: Vec<usize>,
+    /// Children are one level below their parent, followers on the same level
+    pub depth: usize,
+    /// The root of the tree this node is in
+    pub root: usize,
+}
+
+/// A line of the rendered tree
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Row {
+    Node(usize),
+    /// A run of consecutive siblings shown as one line, without their subtrees
+    Collapsed(Vec<usize>),
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TraceTree {
+    nodes: Vec<TreeNode>,
+    roots: Vec<usize>,
+}
+
+impl TraceTree {
+    pub fn new<'a, I: IntoIterator<Item = &'a FinishedSpan>>(spans: I) -> Self {
+        let mut spans: Vec<&FinishedSpan> = spans.into_iter().collect();
+        spans.sort_by_key(|s| (s.start_time(), span_id(s)));
+        let index: HashMap<u64, usize> = spans
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (span_id(s), i))
+            .collect();
+        let mut nodes: Vec<TreeNode> = spans
+            .iter()
+            .map(|s| TreeNode {
+                span_id: span_id(s),
+                start_time: s.start_time(),
+                placement: Placement::Root,
+                parent: None,
+                children: Vec::new(),
+                followers: Vec::new(),
+                depth: 0,
+                root: 0,
+            })
+            .collect();
+        // Iterating in start order keeps every list of children in start order
+        for (i, span) in spans.iter().enumerate() {
+            let present = |child_of: bool| {
+                span.references()
+                    .iter()
+                    .filter(|r| r.is_child_of() == child_of)
+                    .filter_map(|r| index.get(&r.span().span_id()).copied())
+                    .find(|p| *p != i)
+            };
+            if let Some(parent) = present(true) {
+                nodes[i].placement = Placement::Child;
+                nodes[i].parent = Some(parent);
+                nodes[parent].children.push(i);
+            } else if let Some(leader) = present(false) {
+                nodes[i].placement = Placement::Follower;
+                nodes[i].parent = Some(leader);
+                nodes[leader].followers.push(i);
+            }
+        }
+        let mut tree = TraceTree {
+            nodes,
+            roots: Vec::new(),
+        };
+        let mut placed = vec![false; tree.nodes.len()];
+        let roots: Vec<usize> = (0..tree.nodes.len())
+            .filter(|i| tree.nodes[*i].parent.is_none())
+            .collect();
+        for root in roots {
+            tree.place(root, &mut placed);
+        }
+        // Whatever is left hangs off a cycle
+        for i in 0..tree.nodes.len() {
+            if !placed[i] {
+                tree.detach(i);
+                tree.place(i, &mut placed);
+            }
+        }
+        // Nodes are numbered in start order
+        tree.roots.sort_unstable();
+        tree
+    }
+
+    pub fn node(&self, i: usize) -> &TreeNode {
+        &self.nodes[i]
+    }
+
+    /// Root nodes in start order
+    pub fn roots(&self) -> &[usize] {
+        &self.roots
+    }
+
+    /// The subtree of `start` in rendering order
+    pub fn walk_from(&self, start: usize, order: &mut Vec<usize>) {
+        let mut pending = vec![start];
+        while let Some(i) = pending.pop() {
+            order.push(i);
+            let node = &self.nodes[i];
+            pending.extend(node.followers.iter().rev());
+            pending.extend(node.children.iter().rev());
+        }
+    }
+
+    /// Like `walk_from`, but with each run of consecutive children, or of
+    /// consecutive followers, for which `same` holds pairwise with the first
+    /// of the run collapsed into one row
+    pub fn rows_from(
+        &self,
+        start: usize,
+        same: &dyn Fn(usize, usize) -> bool,
+        rows: &mut Vec<Row>,
+    ) {
+        let runs = |siblings: &[usize]| {
+            let mut runs: Vec<Vec<usize>> = Vec::new();
+            for sibling in siblings {
+                match runs.last_mut() {
+                    Some(run) if same(run[0], *sibling) => run.push(*sibling),
+                    _ => runs.push(vec![*sibling]),
+                }
+            }
+            runs.into_iter().map(|mut run| {
+                if run.len() == 1 {
+                    Row::Node(run.pop().expect("Runs are never empty"))
+                } else {
+                    Row::Collapsed(run)
+                }
+            })
+        };
+        let mut pending = vec![Row::Node(start)];
+        while let Some(row) = pending.pop() {
+            if let Row::Node(i) = &row {
+                let node = &self.nodes[*i];
+                let below: Vec<Row> = runs(&node.children).chain(runs(&node.followers)).collect();
+                pending.extend(below.into_iter().rev());
+            }
+            rows.push(row);
+        }
+    }
+
+    /// Make `root` a root, and set depth and root of everything below it
+    fn place(&mut self, root: usize, placed: &mut [bool]) {
+        self.roots.push(root);
+        let mut pending = vec![(root, 0)];
+        while let Some((i, depth)) = pending.pop() {
+            placed[i] = true;
+            let node = &mut self.nodes[i];
+            node.depth = depth;
+            node.root = root;
+            pending.extend(node.children.iter().map(|c| (*c, depth + 1)));
+            pending.extend(node.followers.iter().map(|f| (*f, depth)));
+        }
+    }
+
+    /// Cut `i` loose from its parent, to break a cycle
+    fn detach(&mut self, i: usize) {
+        if let Some(parent) = self.nodes[i].parent.take() {
+            let parent = &mut self.nodes[parent];
+            parent.children.retain(|c| *c != i);
+            parent.followers.retain(|f| *f != i);
+        }
+        self.nodes[i].placement = Placement::Root;
+    }
+}
+
+fn span_id(span: &FinishedSpan) -> u64 {
+    span.context().state().span_id()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AllSampler, Tracer};
+    use rustracing::span::SpanContext;
+    use rustracing_jaeger::span::SpanContextStateBuilder;
+
+    /// A span with id `id` which is a child of `parent`, whether or not it exists
+    fn span(tracer: &Tracer, name: &'static str, id: u64, parent: Option<u64>) {
+        let state = |id| SpanContextStateBuilder::new().span_id(id).finish();
+        let mut options = tracer.span(name);
+        let parent = parent.map(|p| SpanContext::new(state(p), vec![]));
+        if let Some(parent) = parent.as_ref() {
+            options = options.child_of(parent);
+        }
+        drop(options.start_with_state(state(id)));
+    }
+
+    fn walk(tree: &TraceTree) -> Vec<usize> {
+        let mut order = Vec::new();
+        for root in tree.roots() {
+            tree.walk_from(*root, &mut order);
+        }
+        order
+    }
+
+    fn names(tree: &TraceTree, spans: &HashMap<u64, FinishedSpan>) -> Vec<(String, usize)> {
+        walk(tree)
+            .into_iter()
+            .map(|i| {
+                let node = tree.node(i);
+                let name = spans[&node.span_id].operation_name().to_string();
+                (name, node.depth)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_cycles_and_missing_parents() {
+        let (tracer, span_rx) = Tracer::new(AllSampler);
+        span(&tracer, "a", 1, Some(2));
+        span(&tracer, "b", 2, Some(3));
+        span(&tracer, "c", 3, Some(1));
+        span(&tracer, "d", 4, Some(2));
+        span(&tracer, "orphan", 5, Some(99));
+        span(&tracer, "self", 6, Some(6));
+        let spans: HashMap<u64, FinishedSpan> =
+            span_rx.try_iter().map(|s| (span_id(&s), s)).collect();
+        let tree = TraceTree::new(spans.values());
+        let names = names(&tree, &spans);
+        assert_eq!(names.len(), 6);
+        // The cycle is broken at its earliest span, "a"
+        let a = names.iter().position(|n| n.0 == "a").unwrap();
+        assert_eq!(
+            names[a..a + 4].to_vec(),
+            vec![
+                ("a".to_string(), 0),
+                ("c".to_string(), 1),
+                ("b".to_string(), 2),
+                ("d".to_string(), 3)
+            ]
+        );
+        assert!(names.contains(&("orphan".to_string(), 0)));
+        assert!(names.contains(&("self".to_string(), 0)));
+    }
+
+    #[test]
+    fn test_deep_trace() {
+        let (tracer, span_rx) = Tracer::new(AllSampler);
+        let depth = 200_000;
+        for id in 1..=depth {
+            span(&tracer, "link", id, Some(id - 1).filter(|p| *p > 0));
+        }
+        let spans: Vec<FinishedSpan> = span_rx.try_iter().collect();
+        let tree = TraceTree::new(spans.iter());
+        assert_eq!(tree.roots().len(), 1);
+        let order = walk(&tree);
+        assert_eq!(order.len(), depth as usize);
+        assert_eq!(tree.node(*order.last().unwrap()).depth, depth as usize - 1);
+    }
+}
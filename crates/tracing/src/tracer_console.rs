@@ -1,8 +1,21 @@
+use crate::conventions;
+use crate::deterministic::{Clock, DeterministicTracer, IdGenerator};
+use crate::export::tag_string;
+use crate::pretty::{self, Line};
+use crate::query::{SpanGraph, SpanQuery};
+use crate::trace_tree::{Placement, Row, TraceTree};
 use crate::Tracer;
+use rustracing::log::Log;
 pub use rustracing::sampler::AllSampler;
-use rustracing::span::{FinishedSpan as RtFinishedSpan, SpanReference::*};
-use rustracing_jaeger::span::SpanContextState;
-use std::collections::{BTreeMap, HashMap};
+use rustracing::span::FinishedSpan as RtFinishedSpan;
+use rustracing::tag::TagValue;
+use rustracing_jaeger::span::{SpanContextState, TraceId};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 /// Create a Tracer and Reporter that reports all spans in ASCII form
 pub fn new_tracer_with_console_reporter() -> (Tracer, ConsoleReporter) {
@@ -11,12 +24,25 @@ pub fn new_tracer_with_console_reporter() -> (Tracer, ConsoleReporter) {
     (tracer, ConsoleReporter::new(span_rx))
 }
 
+/// Like `new_tracer_with_console_reporter`, but spans started from the tracer
+/// take their ids from `ids` and times from `clock`, so that rendering them is
+/// byte-stable across runs. See the `deterministic` module.
+pub fn new_tracer_with_deterministic_console_reporter<C: Clock + 'static>(
+    ids: IdGenerator,
+    clock: C,
+) -> (DeterministicTracer, ConsoleReporter) {
+    let (tracer, reporter) = new_tracer_with_console_reporter();
+    (DeterministicTracer::new(tracer, ids, clock), reporter)
+}
+
 /// A Reporter that stores all spans it receives in a map,
 /// with the intent to display all received spans to the console.
+/// The map is shared, so spans can be collected on a background thread
+/// (see `drain_in_background`) while the reporter is printed.
 #[derive(Debug)]
 pub struct ConsoleReporter {
     span_rx: crossbeam_channel::Receiver<FinishedSpan>,
-    span_map: SpanMap,
+    store: Arc<Mutex<SpanStore>>,
 }
 
 impl ConsoleReporter {
@@ -24,216 +50,1338 @@ impl ConsoleReporter {
     pub fn new(span_rx: crossbeam_channel::Receiver<FinishedSpan>) -> Self {
         ConsoleReporter {
             span_rx,
-            span_map: HashMap::new(),
+            store: Default::default(),
         }
     }
 
+    /// Limit the spans kept, see `Retention`
+    pub fn with_retention(self, retention: Retention) -> Self {
+        self.set_retention(retention);
+        self
+    }
+
+    /// Change the limits on the spans kept, evicting right away if needed
+    pub fn set_retention(&self, retention: Retention) {
+        let mut store = self.lock();
+        store.retention = retention;
+        store.evict(SystemTime::now());
+    }
+
+    /// The limits on the spans kept
+    pub fn retention(&self) -> Retention {
+        self.lock().retention
+    }
+
+    /// What retention has evicted so far. If this is not zero, printouts may
+    /// be missing traces.
+    pub fn evicted(&self) -> Evicted {
+        self.lock().evicted
+    }
+
     /// Delete all stored spans
     pub fn clear(&mut self) {
         let _ = self.drain();
-        self.span_map.clear();
+        let mut store = self.lock();
+        store.spans.clear();
+        store.traces.clear();
+        store.by_finish.clear();
+        store.tree = None;
     }
 
     /// Drain `span_rx` and add to map
-    /// TODO: Could be done periodically in a separate thread
     pub fn drain(&mut self) -> u32 {
-        let mut count = 0;
-        while let Ok(span) = self.span_rx.try_recv() {
-            count += 1;
-            self.span_map.insert(span.context().state().span_id(), span);
+        drain_into(&self.span_rx, &self.store)
+    }
+
+    /// Keep draining `span_rx` on a background thread, so that long runs don't fill
+    /// up the channel and lose spans. The thread wakes up at least every `interval`
+    /// to check whether it has been stopped and to evict traces past their
+    /// maximum age. Printing the reporter stays safe while
+    /// the background thread is running.
+    pub fn drain_in_background(&self, interval: Duration) -> BackgroundDrain {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let span_rx = self.span_rx.clone();
+            let store = self.store.clone();
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("console-reporter-drain".into())
+                .spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        match span_rx.recv_timeout(interval) {
+                            Ok(span) => {
+                                let spans = std::iter::once(span).chain(span_rx.try_iter());
+                                store_spans(&store, spans);
+                            }
+                            Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                                lock_store(&store).evict(SystemTime::now())
+                            }
+                            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => break,
+                        }
+                    }
+                })
+                .expect("Failed to spawn drain thread")
+        };
+        BackgroundDrain {
+            span_rx: self.span_rx.clone(),
+            store: self.store.clone(),
+            stop,
+            thread: Some(thread),
         }
-        count
+    }
+
+    /// Number of spans stored
+    pub fn len(&self) -> usize {
+        self.lock().spans.len()
+    }
+
+    /// True if no spans are stored
+    pub fn is_empty(&self) -> bool {
+        self.lock().spans.is_empty()
+    }
+
+    /// Run `f` over the stored spans, e.g. to hand them to an exporter.
+    /// Background draining waits until `f` returns.
+    pub fn with_spans<R, F: FnOnce(Spans<'_>) -> R>(&self, f: F) -> R {
+        f(Spans(self.lock().spans.values()))
+    }
+
+    /// The stored spans matching `query`, ordered by start time
+    pub fn query(&self, query: &SpanQuery) -> Vec<Arc<FinishedSpan>> {
+        let mut matching: Vec<_> = self
+            .lock()
+            .spans
+            .values()
+            .filter(|s| query.matches(s))
+            .cloned()
+            .collect();
+        matching.sort_by_key(|s| (s.start_time(), s.context().state().span_id()));
+        matching
+    }
+
+    /// A snapshot of the stored spans, to walk the relationships between them
+    pub fn graph(&self) -> SpanGraph {
+        SpanGraph::new(self.lock().spans.values().cloned())
     }
 
     /// Print span_map to console
     pub fn print(&self, only_events: bool) {
-        print_span_map(&self.span_map, only_events);
+        self.print_with(&RenderOptions::only_events(only_events));
+    }
+
+    /// Print span_map to console with the given options
+    pub fn print_with(&self, options: &RenderOptions) {
+        let stdout = io::stdout();
+        self.render_with(&mut stdout.lock(), options)
+            .expect("Failed to print spans to stdout");
+    }
+
+    /// Write span_map as a tree to any writer, in the same form as `print`
+    pub fn render<W: Write>(&self, writer: &mut W, only_events: bool) -> io::Result<()> {
+        self.render_with(writer, &RenderOptions::only_events(only_events))
+    }
+
+    /// Write span_map as a tree to any writer with the given options
+    pub fn render_with<W: Write>(&self, writer: &mut W, options: &RenderOptions) -> io::Result<()> {
+        let mut store = self.lock();
+        let (spans, tree) = store.indexed();
+        if options.by_trace && !options.only_events {
+            write_traces(writer, spans, tree, options)
+        } else {
+            write_span_map(writer, spans, tree, options)
+        }
+    }
+
+    /// Render span_map as a tree into a String, in the same form as `print`
+    pub fn render_to_string(&self, only_events: bool) -> String {
+        self.render_to_string_with(&RenderOptions::only_events(only_events))
+    }
+
+    /// Render span_map as a tree into a String with the given options
+    pub fn render_to_string_with(&self, options: &RenderOptions) -> String {
+        let mut out = Vec::new();
+        self.render_with(&mut out, options)
+            .expect("Writing to a Vec can't fail");
+        String::from_utf8(out).expect("Rendered spans are always UTF-8")
+    }
+
+    /// A summary of each trace, in the order `print` shows them with `by_trace`
+    pub fn traces(&self) -> Vec<TraceSummary> {
+        let mut store = self.lock();
+        let (spans, tree) = store.indexed();
+        group_traces(spans, tree)
+            .into_iter()
+            .map(|g| g.summary)
+            .collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SpanStore> {
+        lock_store(&self.store)
+    }
+}
+
+/// Iterator over the spans stored in a `ConsoleReporter`, in no particular order
+pub struct Spans<'a>(std::collections::hash_map::Values<'a, u64, Arc<FinishedSpan>>);
+
+impl<'a> Iterator for Spans<'a> {
+    type Item = &'a FinishedSpan;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|span| &**span)
+    }
+}
+
+/// Options for rendering the span tree
+#[derive(Clone, Debug, Default)]
+pub struct RenderOptions {
+    /// Only show the logs of each span
+    pub only_events: bool,
+    /// Show each span's start offset from its trace root and its duration in
+    /// columns before the tree, and each log's offset from its span's start
+    pub timing: bool,
+    /// Mark spans which took at least this long. Implies `timing`.
+    pub slow_threshold: Option<Duration>,
+    /// Group spans by trace, each under a header and followed by a report of
+    /// the spans it references but which were never received. Roots which
+    /// should have a parent are marked `(orphan)`. Ignored with `only_events`.
+    pub by_trace: bool,
+    /// Show consecutive sibling spans with the same operation name, e.g. from
+    /// a loop, as one line with their count, total, min and max duration.
+    /// Their subtrees and logs are left out. Ignored with `only_events`.
+    pub collapse_repeated: bool,
+    /// How the tree is drawn. Ignored with `only_events`.
+    pub style: TreeStyle,
+    /// Highlight failed spans, slow spans and follower edges with ANSI
+    /// colors. Only used by the `Unicode` and `Ascii` styles.
+    pub color: bool,
+    /// Wrap the tags of a span onto further lines past this many columns.
+    /// Only used by the `Unicode` and `Ascii` styles.
+    pub width: Option<usize>,
+}
+
+/// How the span tree is drawn
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TreeStyle {
+    /// Indent by one tab per level, with tags in `{key = value}` form
+    Tabs,
+    /// Box-drawing connectors, with followers hanging off the span they
+    /// follow by a dashed edge
+    Unicode,
+    /// Like `Unicode`, but in plain ASCII for terminals without box-drawing
+    Ascii,
+}
+
+impl Default for TreeStyle {
+    fn default() -> Self {
+        TreeStyle::Tabs
+    }
+}
+
+impl RenderOptions {
+    /// The options equivalent to `print(only_events)`
+    pub fn only_events(only_events: bool) -> Self {
+        RenderOptions {
+            only_events,
+            ..Default::default()
+        }
+    }
+
+    /// Show timing columns
+    pub fn with_timing() -> Self {
+        RenderOptions {
+            timing: true,
+            ..Default::default()
+        }
+    }
+
+    /// Box-drawing options for the terminal this process runs in. Colors are
+    /// off if `NO_COLOR` is set, and `TERM=dumb` gets the ASCII style without
+    /// colors. The width is taken from `COLUMNS`, or 100 if it's not set.
+    pub fn for_terminal() -> Self {
+        let dumb = std::env::var("TERM").ok().as_deref() == Some("dumb");
+        let no_color = std::env::var_os("NO_COLOR")
+            .filter(|v| !v.is_empty())
+            .is_some();
+        let width = std::env::var("COLUMNS")
+            .ok()
+            .and_then(|columns| columns.trim().parse().ok())
+            .filter(|columns| *columns > 0)
+            .unwrap_or(DEFAULT_WIDTH);
+        RenderOptions {
+            style: if dumb {
+                TreeStyle::Ascii
+            } else {
+                TreeStyle::Unicode
+            },
+            color: !dumb && !no_color,
+            width: Some(width),
+            ..Default::default()
+        }
+    }
+
+    fn pretty(&self) -> bool {
+        self.style != TreeStyle::Tabs && !self.only_events
+    }
+
+    fn timing(&self) -> bool {
+        self.timing || self.slow_threshold.is_some()
+    }
+
+    fn is_slow(&self, duration: Duration) -> bool {
+        self.slow_threshold
+            .map(|threshold| duration >= threshold)
+            .unwrap_or(false)
+    }
+}
+
+/// Summary of one trace among the collected spans
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceSummary {
+    pub trace_id: TraceId,
+    /// Operation name of the trace's root span
+    pub root: String,
+    /// Number of spans collected
+    pub spans: usize,
+    /// From the earliest start to the latest finish of its spans
+    pub duration: Duration,
+    /// Spans whose parent or the span they follow from was never received,
+    /// sorted by id
+    pub orphans: Vec<u64>,
+    /// Parents referenced by spans but never received, whether lost or still running
+    pub missing_parents: Vec<u64>,
+    /// Spans followed from but never received, typically because they are still running
+    pub unfinished_references: Vec<u64>,
+}
+
+impl TraceSummary {
+    /// True if every span referenced in the trace was received
+    pub fn is_complete(&self) -> bool {
+        self.missing_parents.is_empty() && self.unfinished_references.is_empty()
+    }
+}
+
+/// Handle to a thread draining spans into a `ConsoleReporter`.
+/// Dropping the handle stops the thread, without a final flush.
+pub struct BackgroundDrain {
+    span_rx: crossbeam_channel::Receiver<FinishedSpan>,
+    store: Arc<Mutex<SpanStore>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl BackgroundDrain {
+    /// Drain whatever is waiting in the channel right now, without waiting for
+    /// the background thread to get to it. Returns the number of spans drained.
+    pub fn flush(&self) -> u32 {
+        drain_into(&self.span_rx, &self.store)
+    }
+
+    /// Stop the background thread, then flush any spans it left behind.
+    /// Returns the number of spans drained by the final flush.
+    pub fn stop(mut self) -> u32 {
+        self.join();
+        self.flush()
+    }
+
+    fn join(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for BackgroundDrain {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// Limits on the spans a `ConsoleReporter` keeps. Spans are evicted a whole
+/// trace at a time, least recently finished trace first, so a printout never
+/// shows part of a trace. Spans of a trace which arrive after it was evicted
+/// start it afresh.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Evict traces until at most this many spans are kept. The most recently
+    /// finished trace is kept whole even if it is larger on its own.
+    pub max_spans: Option<usize>,
+    /// Evict traces whose last span finished longer ago than this.
+    /// Ages are measured on the system clock, so leave this unset when
+    /// spans take their times from a `deterministic` clock.
+    pub max_age: Option<Duration>,
+}
+
+impl Retention {
+    /// Keep everything, the default
+    pub fn unlimited() -> Self {
+        Default::default()
+    }
+}
+
+/// Counts of the spans and traces evicted by a `ConsoleReporter`'s `Retention`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Evicted {
+    pub spans: u64,
+    pub traces: u64,
+}
+
+/// The state shared by a reporter and its background drain
+#[derive(Debug, Default)]
+struct SpanStore {
+    spans: SpanMap,
+    /// The spans of each trace, kept up to date as spans are inserted
+    traces: HashMap<TraceId, StoredTrace>,
+    /// Traces by the time their last span finished, oldest first
+    by_finish: BTreeSet<(SystemTime, TraceId)>,
+    retention: Retention,
+    evicted: Evicted,
+    /// Built when first needed after the spans change
+    tree: Option<TraceTree>,
+}
+
+#[derive(Debug)]
+struct StoredTrace {
+    last_finish: SystemTime,
+    span_ids: Vec<u64>,
+}
+
+impl SpanStore {
+    fn insert(&mut self, span: FinishedSpan) {
+        let span_id = span.context().state().span_id();
+        let trace_id = span.context().state().trace_id();
+        let finish = span.finish_time();
+        if let Some(old) = self.spans.insert(span_id, Arc::new(span)) {
+            // The same span reported twice, or an id collision
+            self.unindex(old.context().state().trace_id(), span_id);
+        }
+        let trace = self.traces.entry(trace_id).or_insert_with(|| StoredTrace {
+            last_finish: finish,
+            span_ids: Vec::new(),
+        });
+        self.by_finish.remove(&(trace.last_finish, trace_id));
+        trace.last_finish = trace.last_finish.max(finish);
+        trace.span_ids.push(span_id);
+        self.by_finish.insert((trace.last_finish, trace_id));
+    }
+
+    /// Remove a replaced span from its trace's index. The trace keeps its last
+    /// finish time, so it is evicted no earlier than it would have been.
+    fn unindex(&mut self, trace_id: TraceId, span_id: u64) {
+        let emptied = match self.traces.get_mut(&trace_id) {
+            Some(trace) => {
+                trace.span_ids.retain(|id| *id != span_id);
+                trace.span_ids.is_empty()
+            }
+            None => false,
+        };
+        if emptied {
+            let trace = self.traces.remove(&trace_id).expect("Trace is indexed");
+            self.by_finish.remove(&(trace.last_finish, trace_id));
+        }
+    }
+
+    /// The spans and their tree, building the tree if the spans changed
+    fn indexed(&mut self) -> (&SpanMap, &TraceTree) {
+        let SpanStore { spans, tree, .. } = self;
+        let tree = tree.get_or_insert_with(|| TraceTree::new(spans.values().map(|s| &**s)));
+        (spans, tree)
+    }
+
+    /// Evict whole traces according to the retention limits
+    fn evict(&mut self, now: SystemTime) {
+        if self.retention == Retention::unlimited() {
+            return;
+        }
+        while let Some(&(last_finish, trace_id)) = self.by_finish.iter().next() {
+            let too_old = match self.retention.max_age {
+                Some(max_age) => elapsed(last_finish, now) > max_age,
+                None => false,
+            };
+            let too_many = match self.retention.max_spans {
+                Some(max_spans) => self.spans.len() > max_spans && self.by_finish.len() > 1,
+                None => false,
+            };
+            // Traces are in order of age, so once one is kept so are the rest
+            if !too_old && !too_many {
+                break;
+            }
+            self.by_finish.remove(&(last_finish, trace_id));
+            let span_ids = self
+                .traces
+                .remove(&trace_id)
+                .expect("Traces are indexed with their finish time")
+                .span_ids;
+            for span_id in span_ids.iter() {
+                self.spans.remove(span_id);
+            }
+            self.evicted.spans += span_ids.len() as u64;
+            self.evicted.traces += 1;
+            self.tree = None;
+        }
+    }
+}
+
+/// A panic while printing must not stop collection, so ignore poisoning
+fn lock_store(store: &Mutex<SpanStore>) -> MutexGuard<'_, SpanStore> {
+    store
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Insert a batch of spans, then apply retention
+fn store_spans<I: Iterator<Item = FinishedSpan>>(store: &Mutex<SpanStore>, spans: I) -> u32 {
+    let mut store = lock_store(store);
+    let mut count = 0;
+    for span in spans {
+        store.insert(span);
+        count += 1;
+    }
+    if count > 0 {
+        store.tree = None;
     }
+    store.evict(SystemTime::now());
+    count
+}
+
+fn drain_into(
+    span_rx: &crossbeam_channel::Receiver<FinishedSpan>,
+    store: &Mutex<SpanStore>,
+) -> u32 {
+    store_spans(store, span_rx.try_iter())
 }
 
 /// Sugar, as we are using rusttracing specifically with rustracing_jaeger
 type FinishedSpan = RtFinishedSpan<SpanContextState>;
 /// A HashMap of finished span. Key is span_id.
-type SpanMap = std::collections::HashMap<u64, FinishedSpan>;
+/// Spans are shared so that queries can hand them out without holding the lock.
+type SpanMap = std::collections::HashMap<u64, Arc<FinishedSpan>>;
+
+/// Width of each timing column
+const TIMING_WIDTH: usize = 12;
+
+/// Terminal width assumed by `RenderOptions::for_terminal` without `COLUMNS`
+const DEFAULT_WIDTH: usize = 100;
+
+pub(crate) fn span_duration(span: &FinishedSpan) -> Duration {
+    elapsed(span.start_time(), span.finish_time())
+}
+
+/// Time from `earlier` to `later`, or zero if the clock went backwards
+fn elapsed(earlier: SystemTime, later: SystemTime) -> Duration {
+    later.duration_since(earlier).unwrap_or_default()
+}
+
+/// Milliseconds with microsecond precision, e.g. "12.345ms"
+pub(crate) fn format_duration(duration: Duration) -> String {
+    format!(
+        "{}.{:03}ms",
+        duration.as_millis(),
+        duration.subsec_micros() % 1000
+    )
+}
+
+/// The timing columns for a span: offset from the trace root, duration, slow marker
+fn timing_columns(
+    start: SystemTime,
+    duration: Duration,
+    slow: bool,
+    root_start: SystemTime,
+) -> String {
+    format!(
+        "{:>width$} {:>width$} {} ",
+        format!("+{}", format_duration(elapsed(root_start, start))),
+        format_duration(duration),
+        if slow { "SLOW" } else { "    " },
+        width = TIMING_WIDTH
+    )
+}
+
+/// Blank space lining logs up with the tree after the timing columns
+fn timing_padding() -> String {
+    " ".repeat(2 * TIMING_WIDTH + 7)
+}
 
-/// Print a span but only its logs
-fn print_span_events(span: &FinishedSpan) {
+/// The offset of a log from its span's start, e.g. "[+1.200ms] "
+fn log_offset(span: &FinishedSpan, log: &Log, options: &RenderOptions) -> String {
+    if options.timing() {
+        format!(
+            "[+{}] ",
+            format_duration(elapsed(span.start_time(), log.time()))
+        )
+    } else {
+        String::new()
+    }
+}
+
+/// Write a span but only its logs
+fn write_span_events(
+    w: &mut dyn Write,
+    span: &FinishedSpan,
+    options: &RenderOptions,
+) -> io::Result<()> {
     for log in span.logs() {
         for field in log.fields() {
-            println!("{}", field.value());
+            writeln!(w, "{}{}", log_offset(span, log, options), field.value())?;
         }
     }
+    Ok(())
 }
 
-/// Print a single span
-fn print_span(span_map: &SpanMap, span: &FinishedSpan, only_events: bool) {
-    if only_events {
-        print_span_events(span);
+/// Write a single span
+fn write_span(
+    w: &mut dyn Write,
+    span: &FinishedSpan,
+    marker: &str,
+    span_depth: usize,
+    root_start: SystemTime,
+    options: &RenderOptions,
+) -> io::Result<()> {
+    if options.only_events {
+        write_span_events(w, span, options)?;
     } else {
-        let span_id = span.context().state().span_id();
-        let (span_depth, _span_offset) =
-            get_span_position(span_map, span_id).expect("span not part of span_map");
-        let mut spacing = String::new();
-        for _ in 0..span_depth {
-            spacing.push_str("\t");
-        }
+        let spacing = "\t".repeat(span_depth);
         let mut tags = String::new();
         for tag in span.tags() {
             tags.push_str(&format!("{{{} = {:?}}} ", tag.name(), tag.value()));
         }
-        println!("{}[{}] {}", spacing, span.operation_name(), tags);
+        let (columns, padding) = if options.timing() {
+            let duration = span_duration(span);
+            let slow = options.is_slow(duration);
+            let columns = timing_columns(span.start_time(), duration, slow, root_start);
+            (columns, timing_padding())
+        } else {
+            (String::new(), String::new())
+        };
+        writeln!(
+            w,
+            "{}{}{}[{}] {}",
+            columns,
+            spacing,
+            marker,
+            span.operation_name(),
+            tags
+        )?;
         for log in span.logs() {
             for field in log.fields() {
-                println!("{}!{}! {}", spacing, field.name(), field.value());
+                writeln!(
+                    w,
+                    "{}{}!{}! {}{}",
+                    padding,
+                    spacing,
+                    field.name(),
+                    log_offset(span, log, options),
+                    field.value()
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the spans as a tree
+fn write_span_map(
+    w: &mut dyn Write,
+    span_map: &SpanMap,
+    tree: &TraceTree,
+    options: &RenderOptions,
+) -> io::Result<()> {
+    for root in tree.roots() {
+        write_tree(w, span_map, tree, *root, &[], options)?;
+    }
+    Ok(())
+}
+
+/// Write the tree under `root`, marking the spans in the sorted `orphans`
+fn write_tree(
+    w: &mut dyn Write,
+    span_map: &SpanMap,
+    tree: &TraceTree,
+    root: usize,
+    orphans: &[u64],
+    options: &RenderOptions,
+) -> io::Result<()> {
+    let root_start = tree.node(root).start_time;
+    let span = |i: usize| &span_map[&tree.node(i).span_id];
+    let same = |a: usize, b: usize| {
+        options.collapse_repeated
+            && !options.only_events
+            && span(a).operation_name() == span(b).operation_name()
+    };
+    let mut rows = Vec::new();
+    tree.rows_from(root, &same, &mut rows);
+    if options.pretty() {
+        let lines = pretty_lines(span_map, tree, &rows, orphans, root_start, options);
+        return pretty::write_lines(w, &lines, options.style, options.color, options.width);
+    }
+    for row in rows {
+        match row {
+            Row::Node(i) => {
+                let node = tree.node(i);
+                let marker = if is_orphan(orphans, node.span_id) {
+                    "(orphan) "
+                } else {
+                    ""
+                };
+                write_span(w, span(i), marker, node.depth, root_start, options)?;
+            }
+            Row::Collapsed(nodes) => {
+                let spans: Vec<&FinishedSpan> = nodes.iter().map(|i| &**span(*i)).collect();
+                let depth = tree.node(nodes[0]).depth;
+                write_collapsed(w, &spans, depth, root_start, options)?;
             }
         }
     }
+    Ok(())
 }
 
-/// Print a single span with it's hierachy
-/// TODO: this was moved from public to private, but is this used externally?
-fn _print_span_stack(span_map: &SpanMap, span_id: u64) {
-    let maybe_span = span_map.get(&span_id);
-    let span = match maybe_span {
-        None => return,
-        Some(s) => s,
+/// The rows of a tree as lines for the box-drawing styles. Followers are
+/// drawn one level below the span they follow rather than beside it.
+fn pretty_lines(
+    span_map: &SpanMap,
+    tree: &TraceTree,
+    rows: &[Row],
+    orphans: &[u64],
+    root_start: SystemTime,
+    options: &RenderOptions,
+) -> Vec<Line> {
+    let span = |i: usize| &span_map[&tree.node(i).span_id];
+    let mut depths: HashMap<usize, usize> = HashMap::new();
+    let mut depth_of = |i: usize| {
+        let node = tree.node(i);
+        let depth = node
+            .parent
+            .and_then(|parent| depths.get(&parent))
+            .map_or(0, |depth| depth + 1);
+        depths.insert(i, depth);
+        (depth, node.placement == Placement::Follower)
     };
-    for span_ref in span.references() {
-        match span_ref {
-            ChildOf(parent) => {
-                _print_span_stack(span_map, parent.span_id());
-            }
-            FollowsFrom(sibling) => {
-                _print_span_stack(span_map, sibling.span_id());
-            }
-        }
-    }
-    print_span(span_map, span, false);
-}
-
-/// Print the span_map as a tree
-fn print_span_map(span_map: &SpanMap, only_events: bool) {
-    // Create children & sibling map
-    let mut sibling_map = HashMap::new();
-    let mut children_map = HashMap::new();
-    // BTree of root spans by start_time.
-    // Many Spans can have same start_time, so tree node stores a list of span_ids.
-    let mut root_span_list = BTreeMap::new();
-    // For each span, browse span-references to fill children & sibling map
-    for (span_id, span) in span_map {
-        // Handle root span case first
-        let (span_depth, span_offset) =
-            get_span_position(span_map, *span_id).expect("span not part of span_map");
-        let is_root = span_depth == 0 && span_offset == 0;
-        if is_root {
-            let maybe_entry = root_span_list.get(&span.start_time());
-            let mut span_id_list: Vec<u64> = maybe_entry.unwrap_or(&Vec::new()).to_vec();
-            span_id_list.push(*span_id);
-            root_span_list.insert(span.start_time(), span_id_list);
-        }
-        // Browse span-references
-        for span_ref in span.references() {
-            match span_ref {
-                ChildOf(parent) => {
-                    let maybe_children_tree = children_map.remove(&parent.span_id());
-                    let mut children_tree: BTreeMap<_, _> = maybe_children_tree.unwrap_or_default();
-                    let maybe_entry = children_tree.get(&span.start_time());
-                    let mut span_id_list = maybe_entry.unwrap_or(&Vec::new()).to_vec();
-                    span_id_list.push(*span_id);
-                    children_tree.insert(span.start_time(), span_id_list);
-                    //println!("Parent of {} has children: {:?}", span.operation_name(), tree);
-                    children_map.insert(parent.span_id(), children_tree);
-                }
-                FollowsFrom(sibling) => {
-                    let maybe_sibling_tree = sibling_map.remove(&sibling.span_id());
-                    let mut sibling_tree: BTreeMap<_, _> = maybe_sibling_tree.unwrap_or_default();
-                    let maybe_entry = sibling_tree.get(&span_offset);
-                    let mut span_id_list = maybe_entry.unwrap_or(&Vec::new()).to_vec();
-                    span_id_list.push(*span_id);
-                    sibling_tree.insert(span_offset, span_id_list);
-                    sibling_map.insert(sibling.span_id(), sibling_tree);
+    let columns = |start: SystemTime, duration: Duration, slow: bool| {
+        if options.timing() {
+            (
+                timing_columns(start, duration, slow, root_start),
+                timing_padding(),
+            )
+        } else {
+            (String::new(), String::new())
+        }
+    };
+    let mut lines = Vec::new();
+    for row in rows {
+        match row {
+            Row::Node(i) => {
+                let span = span(*i);
+                let (depth, follower) = depth_of(*i);
+                let duration = span_duration(span);
+                let slow = options.is_slow(duration);
+                let (columns, padding) = columns(span.start_time(), duration, slow);
+                let logs = span
+                    .logs()
+                    .iter()
+                    .flat_map(|log| {
+                        log.fields().iter().map(move |field| {
+                            format!(
+                                "{}: {}{}",
+                                field.name(),
+                                log_offset(span, log, options),
+                                field.value()
+                            )
+                        })
+                    })
+                    .collect();
+                lines.push(Line {
+                    depth,
+                    follower,
+                    columns,
+                    padding,
+                    slow,
+                    error: is_error(span),
+                    marker: if is_orphan(orphans, tree.node(*i).span_id) {
+                        "(orphan) ".to_string()
+                    } else {
+                        String::new()
+                    },
+                    name: span.operation_name().to_string(),
+                    tags: span
+                        .tags()
+                        .iter()
+                        .map(|tag| format!("{}={}", tag.name(), tag_string(tag)))
+                        .collect(),
+                    logs,
+                });
+            }
+            Row::Collapsed(nodes) => {
+                let (depth, follower) = depth_of(nodes[0]);
+                for i in nodes.iter().skip(1) {
+                    depth_of(*i);
                 }
+                let spans: Vec<&FinishedSpan> = nodes.iter().map(|i| &**span(*i)).collect();
+                let stats = CollapsedStats::new(&spans);
+                let slow = options.is_slow(stats.max);
+                let (columns, padding) = columns(spans[0].start_time(), stats.total, slow);
+                lines.push(Line {
+                    depth,
+                    follower,
+                    columns,
+                    padding,
+                    slow,
+                    error: spans.iter().any(|s| is_error(s)),
+                    name: format!("{} {}", spans[0].operation_name(), stats),
+                    ..Default::default()
+                });
             }
         }
     }
-    // print span tree
-    for (_start_time, span_id_list) in root_span_list.iter() {
-        for span_id in span_id_list {
-            print_span_tree(&children_map, &sibling_map, span_map, *span_id, only_events);
+    lines
+}
+
+/// Whether a span is tagged as failed
+fn is_error(span: &FinishedSpan) -> bool {
+    span.tags()
+        .iter()
+        .any(|t| t.name() == conventions::ERROR && *t.value() == TagValue::Boolean(true))
+}
+
+/// Durations of a run of repeated sibling spans
+struct CollapsedStats {
+    count: usize,
+    total: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl CollapsedStats {
+    fn new(spans: &[&FinishedSpan]) -> Self {
+        let durations: Vec<Duration> = spans.iter().map(|s| span_duration(s)).collect();
+        CollapsedStats {
+            count: spans.len(),
+            total: durations.iter().sum(),
+            min: durations.iter().min().copied().unwrap_or_default(),
+            max: durations.iter().max().copied().unwrap_or_default(),
         }
     }
 }
 
-/// Recursive tree walking
-fn print_span_tree(
-    children_map: &HashMap<u64, BTreeMap<std::time::SystemTime, Vec<u64>>>,
-    sibling_map: &HashMap<u64, BTreeMap<u32, Vec<u64>>>,
-    span_map: &SpanMap,
-    span_id: u64,
-    only_events: bool,
-) {
-    // print self
-    let span = span_map.get(&span_id).expect("Span not found");
-    print_span(span_map, span, only_events);
-    // print children
-    let maybe_children_tree = children_map.get(&span_id);
-    if let Some(children_tree) = maybe_children_tree {
-        for (_start_time, child_span_id_list) in children_tree.iter() {
-            for child_span_id in child_span_id_list {
-                print_span_tree(
-                    children_map,
-                    sibling_map,
-                    span_map,
-                    *child_span_id,
-                    only_events,
-                );
-            }
-        }
-    }
-    // print siblings
-    let maybe_siblings_tree = sibling_map.get(&span_id);
-    if let Some(sibling_tree) = maybe_siblings_tree {
-        for (_sibling_offset, sibling_span_id_list) in sibling_tree.iter() {
-            for sibling_span_id in sibling_span_id_list {
-                print_span_tree(
-                    children_map,
-                    sibling_map,
-                    span_map,
-                    *sibling_span_id,
-                    only_events,
-                );
-            }
-        }
-    }
-}
-
-/// gives the depth and sibling offset of a span
-fn get_span_position(span_map: &SpanMap, span_id: u64) -> Option<(u32, u32)> {
-    let maybe_span = span_map.get(&span_id);
-    let span = match maybe_span {
-        None => return None,
-        Some(s) => s,
+impl std::fmt::Display for CollapsedStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "x{} (total {}, min {}, max {})",
+            self.count,
+            format_duration(self.total),
+            format_duration(self.min),
+            format_duration(self.max)
+        )
+    }
+}
+
+/// Write a run of repeated sibling spans as one line
+fn write_collapsed(
+    w: &mut dyn Write,
+    spans: &[&FinishedSpan],
+    span_depth: usize,
+    root_start: SystemTime,
+    options: &RenderOptions,
+) -> io::Result<()> {
+    let stats = CollapsedStats::new(spans);
+    let columns = if options.timing() {
+        // Slow if any one of them is, not if they only add up to it
+        let slow = options.is_slow(stats.max);
+        timing_columns(spans[0].start_time(), stats.total, slow, root_start)
+    } else {
+        String::new()
     };
-    let mut depth = 0;
-    let mut offset = 0;
-    for span_ref in span.references() {
-        match span_ref {
-            ChildOf(parent) => {
-                if let Some((parent_depth, _parent_offset)) =
-                    get_span_position(span_map, parent.span_id())
-                {
-                    depth = parent_depth + 1;
+    writeln!(
+        w,
+        "{}{}[{}] {}",
+        columns,
+        "\t".repeat(span_depth),
+        spans[0].operation_name(),
+        stats
+    )
+}
+
+/// The trees of one trace
+struct TraceGroup {
+    summary: TraceSummary,
+    roots: Vec<usize>,
+}
+
+/// Group the trees by the trace of their root, in order of their first root
+fn group_traces(span_map: &SpanMap, tree: &TraceTree) -> Vec<TraceGroup> {
+    let mut groups: Vec<TraceGroup> = Vec::new();
+    let mut by_trace: HashMap<TraceId, usize> = HashMap::new();
+    for root in tree.roots() {
+        let trace_id = span_map[&tree.node(*root).span_id]
+            .context()
+            .state()
+            .trace_id();
+        let group = *by_trace.entry(trace_id).or_insert_with(|| {
+            groups.push(TraceGroup {
+                summary: TraceSummary {
+                    trace_id,
+                    root: String::new(),
+                    spans: 0,
+                    duration: Duration::default(),
+                    orphans: Vec::new(),
+                    missing_parents: Vec::new(),
+                    unfinished_references: Vec::new(),
+                },
+                roots: Vec::new(),
+            });
+            groups.len() - 1
+        });
+        groups[group].roots.push(*root);
+    }
+    for group in groups.iter_mut() {
+        let mut nodes = Vec::new();
+        for root in group.roots.iter() {
+            tree.walk_from(*root, &mut nodes);
+        }
+        let summary = &mut group.summary;
+        let mut start = None;
+        let mut finish = None;
+        for i in nodes.iter() {
+            let span = &span_map[&tree.node(*i).span_id];
+            let span_start = span.start_time();
+            start = Some(start.map_or(span_start, |start: SystemTime| start.min(span_start)));
+            finish = finish.max(Some(span.finish_time()));
+            let mut orphan = false;
+            for reference in span.references() {
+                let referenced = reference.span().span_id();
+                if span_map.contains_key(&referenced) {
+                    continue;
+                }
+                orphan = true;
+                if reference.is_child_of() {
+                    summary.missing_parents.push(referenced);
+                } else {
+                    summary.unfinished_references.push(referenced);
                 }
             }
-            FollowsFrom(sibling) => {
-                if let Some((sibling_depth, sibling_offset)) =
-                    get_span_position(span_map, sibling.span_id())
-                {
-                    depth = sibling_depth;
-                    offset = sibling_offset + 1;
+            if orphan && tree.node(*i).parent.is_none() {
+                summary.orphans.push(tree.node(*i).span_id);
+            }
+        }
+        summary.orphans.sort_unstable();
+        summary.spans = nodes.len();
+        if let (Some(start), Some(finish)) = (start, finish) {
+            summary.duration = elapsed(start, finish);
+        }
+        // The root proper, unless the trace has no span without missing references
+        let root = group
+            .roots
+            .iter()
+            .find(|r| !is_orphan(&summary.orphans, tree.node(**r).span_id))
+            .unwrap_or(&group.roots[0]);
+        summary.root = span_map[&tree.node(*root).span_id]
+            .operation_name()
+            .to_string();
+        for ids in [
+            &mut summary.missing_parents,
+            &mut summary.unfinished_references,
+        ]
+        .iter_mut()
+        {
+            ids.sort_unstable();
+            ids.dedup();
+        }
+    }
+    groups
+}
+
+/// Whether `span_id` is among the sorted `orphans`
+fn is_orphan(orphans: &[u64], span_id: u64) -> bool {
+    orphans.binary_search(&span_id).is_ok()
+}
+
+/// Write the spans as a tree per trace, with a header and a completeness report
+fn write_traces(
+    w: &mut dyn Write,
+    span_map: &SpanMap,
+    tree: &TraceTree,
+    options: &RenderOptions,
+) -> io::Result<()> {
+    let hex = |ids: &[u64]| {
+        ids.iter()
+            .map(|id| format!("{:x}", id))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    for group in group_traces(span_map, tree) {
+        let summary = &group.summary;
+        writeln!(
+            w,
+            "=== trace {} [{}] {} spans {} ===",
+            summary.trace_id,
+            summary.root,
+            summary.spans,
+            format_duration(summary.duration)
+        )?;
+        for root in group.roots.iter() {
+            write_tree(w, span_map, tree, *root, &summary.orphans, options)?;
+        }
+        if summary.is_complete() {
+            writeln!(w, "--- complete")?;
+        } else {
+            let mut missing = Vec::new();
+            if !summary.missing_parents.is_empty() {
+                missing.push(format!("missing parents {}", hex(&summary.missing_parents)));
+            }
+            if !summary.unfinished_references.is_empty() {
+                missing.push(format!(
+                    "unfinished references {}",
+                    hex(&summary.unfinished_references)
+                ));
+            }
+            writeln!(w, "--- incomplete: {}", missing.join("; "))?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustracing::span::SpanContext;
+    use rustracing_jaeger::span::SpanContextStateBuilder;
+    use std::time::Instant;
+
+    #[test]
+    fn test_render_to_string() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        {
+            let parent: crate::Span = tracer.span("parent").start().into();
+            let mut child = parent.child("child");
+            child.set_tag(|| crate::Tag::new("id", "A"));
+            child.event("hello");
+            thread::sleep(Duration::from_millis(2));
+            let _follower = parent.follower("follower");
+        }
+        reporter.drain();
+        assert_eq!(
+            reporter.render_to_string(false),
+            "[parent] \n\t[child] {id = String(\"A\")} \n\t!event! hello\n[follower] \n"
+        );
+        assert_eq!(reporter.render_to_string(true), "hello\n");
+    }
+
+    #[test]
+    fn test_render_timing() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        let t0 = SystemTime::now();
+        let ms = Duration::from_millis;
+        {
+            let mut parent = tracer.span("parent").start_time(t0).start();
+            parent.set_finish_time(|| t0 + ms(20));
+            let mut child = parent.child("child", |o| o.start_time(t0 + ms(2)).start());
+            child.set_finish_time(|| t0 + ms(4));
+            child.log(|l| {
+                l.time(t0 + Duration::from_micros(3500))
+                    .std()
+                    .event("hello");
+            });
+        }
+        reporter.drain();
+        let options = RenderOptions {
+            slow_threshold: Some(ms(10)),
+            ..Default::default()
+        };
+        assert_eq!(
+            reporter.render_to_string_with(&options),
+            format!(
+                "{}\n{}\n{}\n",
+                "    +0.000ms     20.000ms SLOW [parent] ",
+                "    +2.000ms      2.000ms      \t[child] ",
+                "                               \t!event! [+1.500ms] hello",
+            )
+        );
+        assert_eq!(
+            reporter.render_to_string_with(&RenderOptions {
+                only_events: true,
+                timing: true,
+                ..Default::default()
+            }),
+            "[+1.500ms] hello\n"
+        );
+    }
+
+    #[test]
+    fn test_by_trace() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        let t0 = SystemTime::now();
+        let ms = Duration::from_millis;
+        let trace_id = |s: &rustracing_jaeger::Span| s.context().unwrap().state().trace_id();
+        let (first_trace, second_trace) = {
+            let mut first = tracer.span("first").start_time(t0).start();
+            first.set_finish_time(|| t0 + ms(5));
+            let mut child = first.child("child", |o| o.start_time(t0 + ms(1)).start());
+            child.set_finish_time(|| t0 + ms(7));
+            let mut second = tracer.span("second").start_time(t0 + ms(2)).start();
+            second.set_finish_time(|| t0 + ms(3));
+            // A span in the second trace referencing spans which are never sent
+            let lost = |span_id| {
+                let state = SpanContextStateBuilder::new()
+                    .trace_id(trace_id(&second))
+                    .span_id(span_id)
+                    .finish();
+                SpanContext::new(state, vec![])
+            };
+            let mut orphan = tracer
+                .span("orphan")
+                .child_of(&lost(0xabc))
+                .follows_from(&lost(0xdef))
+                .start_time(t0 + ms(4))
+                .start_with_state(lost(1).state().clone());
+            orphan.set_finish_time(|| t0 + ms(6));
+            (trace_id(&first), trace_id(&second))
+        };
+        reporter.drain();
+        let traces = reporter.traces();
+        assert_eq!(traces.len(), 2);
+        assert_eq!(traces[0].trace_id, first_trace);
+        assert_eq!(traces[0].root, "first");
+        assert_eq!(traces[0].spans, 2);
+        assert_eq!(traces[0].duration, ms(7));
+        assert!(traces[0].is_complete());
+        assert_eq!(traces[1].trace_id, second_trace);
+        assert_eq!(traces[1].root, "second");
+        assert_eq!(traces[1].spans, 2);
+        assert_eq!(traces[1].duration, ms(4));
+        assert_eq!(traces[1].orphans, vec![1]);
+        assert_eq!(traces[1].missing_parents, vec![0xabc]);
+        assert_eq!(traces[1].unfinished_references, vec![0xdef]);
+        let options = RenderOptions {
+            by_trace: true,
+            ..Default::default()
+        };
+        let expected = [
+            format!("=== trace {} [first] 2 spans 7.000ms ===", first_trace),
+            "[first] ".into(),
+            "\t[child] ".into(),
+            "--- complete".into(),
+            "".into(),
+            format!("=== trace {} [second] 2 spans 4.000ms ===", second_trace),
+            "[second] ".into(),
+            "(orphan) [orphan] ".into(),
+            "--- incomplete: missing parents abc; unfinished references def".into(),
+            "".into(),
+        ];
+        assert_eq!(
+            reporter.render_to_string_with(&options),
+            expected.join("\n") + "\n"
+        );
+    }
+
+    #[test]
+    fn test_collapse_repeated() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        let t0 = SystemTime::now();
+        let ms = Duration::from_millis;
+        {
+            let mut parent = tracer.span("parent").start_time(t0).start();
+            parent.set_finish_time(|| t0 + ms(20));
+            for i in 0..4 {
+                let start = t0 + ms(1 + 3 * i);
+                let mut iteration = parent.child("iteration", |o| o.start_time(start).start());
+                iteration.set_finish_time(|| start + ms(1 + i % 2));
+                let _inner = iteration.child("inner", |o| o.start_time(start).start());
+            }
+            let _done = parent.child("done", |o| o.start_time(t0 + ms(15)).start());
+        }
+        reporter.drain();
+        let collapsed = RenderOptions {
+            collapse_repeated: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            reporter.render_to_string_with(&collapsed),
+            "[parent] \n\t[iteration] x4 (total 6.000ms, min 1.000ms, max 2.000ms)\n\t[done] \n"
+        );
+        let slow = RenderOptions {
+            slow_threshold: Some(ms(2)),
+            ..collapsed.clone()
+        };
+        assert!(reporter
+            .render_to_string_with(&slow)
+            .contains("    +1.000ms      6.000ms SLOW \t[iteration] x4"));
+        // Expanded again without the flag
+        assert_eq!(
+            reporter.render_to_string(false).matches("[inner]").count(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_box_drawing() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        {
+            let parent: crate::Span = tracer.span("parent").start().into();
+            let mut child = parent.child("child");
+            child.set_tag(|| crate::Tag::new("id", "A"));
+            child.set_tag(|| crate::Tag::new("peer", "some-long-peer-name"));
+            child.event("hello");
+            let mut failed = child.child("failed");
+            failed.set_tag(conventions::error);
+            drop(failed);
+            thread::sleep(Duration::from_millis(2));
+            let _follower = parent.follower("follower");
+        }
+        reporter.drain();
+        let options = RenderOptions {
+            style: TreeStyle::Ascii,
+            width: Some(24),
+            ..Default::default()
+        };
+        assert_eq!(
+            reporter.render_to_string_with(&options),
+            [
+                "parent",
+                "|- child id=A",
+                "|  |  peer=some-long-peer-name",
+                "|  |  event: hello",
+                "|  `- failed error=true",
+                "`~ follower",
+                "",
+            ]
+            .join("\n")
+        );
+        let colored = reporter.render_to_string_with(&RenderOptions {
+            style: TreeStyle::Unicode,
+            color: true,
+            ..Default::default()
+        });
+        assert!(colored.contains("└─ \u{1b}[31mfailed\u{1b}[0m"));
+        assert!(colored.contains("\u{1b}[36m└┄ \u{1b}[0mfollower"));
+        // Only the logs, whatever the style
+        assert_eq!(
+            reporter.render_to_string_with(&RenderOptions {
+                only_events: true,
+                ..options
+            }),
+            "hello\n"
+        );
+    }
+
+    #[test]
+    fn test_retention() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        let reporter_ref = &mut reporter;
+        let mut trace = |name: &'static str, size: usize, finish: SystemTime| {
+            {
+                let mut root = tracer.span(name).start_time(finish).start();
+                root.set_finish_time(|| finish);
+                for _ in 1..size {
+                    let mut child = root.child(name, |o| o.start_time(finish).start());
+                    child.set_finish_time(|| finish);
                 }
             }
+            reporter_ref.drain();
+        };
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(3600);
+        trace("stale", 2, now - 3 * hour);
+        trace("old", 3, now - hour);
+        trace("new", 2, now);
+        trace("newest", 3, now + hour);
+        assert_eq!(reporter.len(), 10);
+        assert_eq!(reporter.evicted(), Evicted::default());
+
+        reporter.set_retention(Retention {
+            max_age: Some(2 * hour),
+            ..Default::default()
+        });
+        assert_eq!(reporter.len(), 8);
+        assert_eq!(
+            reporter.evicted(),
+            Evicted {
+                spans: 2,
+                traces: 1
+            }
+        );
+
+        // The least recently finished trace goes first
+        reporter.set_retention(Retention {
+            max_spans: Some(6),
+            ..Default::default()
+        });
+        assert_eq!(reporter.render_to_string(false).matches("[new]").count(), 2);
+        assert_eq!(reporter.len(), 5);
+        assert_eq!(reporter.evicted().traces, 2);
+
+        // The newest trace is kept whole even when it is over the limit
+        reporter.set_retention(Retention {
+            max_spans: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(reporter.len(), 3);
+        assert_eq!(
+            reporter.evicted(),
+            Evicted {
+                spans: 7,
+                traces: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_retention_late_spans() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        reporter.set_retention(Retention {
+            max_spans: Some(2),
+            ..Default::default()
+        });
+        let now = SystemTime::now();
+        let second = Duration::from_secs(1);
+        let mut root = tracer.span("a").start_time(now).start();
+        {
+            let mut child = root.child("a", |o| o.start_time(now).start());
+            child.set_finish_time(|| now);
+        }
+        reporter.drain();
+        {
+            let mut other = tracer.span("b").start_time(now).start();
+            other.set_finish_time(|| now + second);
+        }
+        reporter.drain();
+        assert_eq!(reporter.len(), 2);
+
+        // Finishing later makes the first trace the newest
+        root.set_finish_time(|| now + 2 * second);
+        drop(root);
+        reporter.drain();
+        let rendered = reporter.render_to_string(false);
+        assert_eq!(rendered.matches("[a]").count(), 2);
+        assert!(!rendered.contains("[b]"));
+        assert_eq!(
+            reporter.evicted(),
+            Evicted {
+                spans: 1,
+                traces: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_background_drain() {
+        let (tracer, reporter) = new_tracer_with_console_reporter();
+        let background = reporter.drain_in_background(Duration::from_millis(10));
+        // More spans than the channel can hold, in batches the drain thread must
+        // pick up on its own for none to be lost.
+        for batch in 1..=5 {
+            for _ in 0..500 {
+                let _span = tracer.span("span").start();
+            }
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while reporter.len() < batch * 500 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+            reporter.print(true);
         }
+        assert_eq!(reporter.len(), 2500);
+        drop(tracer.span("late").start());
+        background.stop();
+        assert_eq!(reporter.len(), 2501);
     }
-    Some((depth, offset))
 }
@@ -0,0 +1,546 @@
+//! A reporter which appends every finished span to a file as one line of JSON,
+//! so that traces from a test run or an incident can be kept and examined
+//! later without a Jaeger agent. `read_spans` turns the lines back into spans.
+//!
+//! Each line holds the service name given to the reporter, the span's trace and
+//! span ids in hex, its operation name, start and finish times in nanoseconds
+//! since the Unix epoch, its references, tags and logs:
+//!
+//! ```text
+//! {"service":"conductor","trace_id":"5f0c..","span_id":"9a21..","operation":"call",
+//!  "start_ns":1583..,"finish_ns":1583..,"references":[{"type":"child_of","trace_id":"5f0c..",
+//!  "span_id":"77e0.."}],"tags":[{"key":"component","value":"conductor"}],
+//!  "logs":[{"time_ns":1583..,"fields":[{"key":"event","value":"hello"}]}]}
+//! ```
+//!
+//! JSON has no NaN or infinity, so such float tags are written as strings with
+//! a type, e.g. `{"key":"ratio","value":"NaN","type":"float"}`.
+//!
+//! Files are rotated logrotate style: `spans.jsonl` is renamed to
+//! `spans.jsonl.1`, any older `spans.jsonl.1` to `spans.jsonl.2` and so on.
+
+use crate::rebuild::{array, context_state, field_str, tag_value, Rebuilder, SpanParts};
+use crate::{AllSampler, FinishedSpan, Tracer};
+use rustracing::log::LogField;
+use rustracing::span::{SpanReference, SpanSender};
+use rustracing::tag::{Tag, TagValue};
+use rustracing_jaeger::span::SpanContextState;
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// When to start a new file. Rotation is checked before each span is written,
+/// so a file is never left empty and an idle reporter doesn't rotate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rotation {
+    /// Rotate before a span would take the file past this many bytes
+    pub max_bytes: Option<u64>,
+    /// Rotate once the reporter has been writing to the file for this long
+    pub max_age: Option<Duration>,
+    /// Delete the oldest rotated files past this many. The file being
+    /// written to doesn't count.
+    pub max_files: Option<usize>,
+}
+
+impl Rotation {
+    /// Write to one file forever, the default
+    pub fn never() -> Self {
+        Default::default()
+    }
+}
+
+/// The thread writing spans to the file. It stops once every sender of its
+/// channel, i.e. every clone of the tracer, is dropped.
+#[derive(Debug)]
+pub struct FileReporter {
+    path: PathBuf,
+    thread: thread::JoinHandle<io::Result<()>>,
+}
+
+impl FileReporter {
+    /// The file spans are written to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Wait for the writer to finish, after the tracer has been dropped, and
+    /// return the error which stopped it, if any
+    pub fn join(self) -> io::Result<()> {
+        self.thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
+/// A sender whose spans are written to `path`, to compose with other sinks,
+/// e.g. `metrics::Metrics::forward`. The file is appended to if it exists.
+pub fn file_reporter_sender<P: AsRef<Path>>(
+    service_name: &str,
+    path: P,
+    rotation: Rotation,
+) -> io::Result<(SpanSender<SpanContextState>, FileReporter)> {
+    let path = path.as_ref().to_path_buf();
+    let mut file = RotatingFile::open(path.clone(), rotation)?;
+    let service_name = service_name.to_string();
+    // Unbounded, since a full channel would silently drop spans
+    let (span_tx, span_rx) = crossbeam_channel::unbounded::<FinishedSpan>();
+    let thread = thread::Builder::new()
+        .name("file-reporter".into())
+        .spawn(move || {
+            let result = write_spans(&mut file, &service_name, &span_rx);
+            if let Err(e) = &result {
+                error!("File reporter stopped writing to {:?}: {}", file.path, e);
+            }
+            result
+        })?;
+    Ok((span_tx, FileReporter { path, thread }))
+}
+
+fn write_spans(
+    file: &mut RotatingFile,
+    service_name: &str,
+    span_rx: &crossbeam_channel::Receiver<FinishedSpan>,
+) -> io::Result<()> {
+    for span in span_rx.iter() {
+        file.write_line(&encode(service_name, &span))?;
+        // Flush whenever caught up, rather than once per span
+        if span_rx.is_empty() {
+            file.flush()?;
+        }
+    }
+    file.flush()
+}
+
+/// Create a Tracer that writes all spans to `path` as JSON lines
+pub fn new_tracer_with_file_reporter<P: AsRef<Path>>(
+    service_name: &str,
+    path: P,
+    rotation: Rotation,
+) -> io::Result<(Tracer, FileReporter)> {
+    let (span_tx, reporter) = file_reporter_sender(service_name, path, rotation)?;
+    Ok((Tracer::with_sender(AllSampler, span_tx), reporter))
+}
+
+/// The file currently written to, and what's needed to tell when to rotate it
+struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    writer: BufWriter<File>,
+    bytes: u64,
+    opened: Instant,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, rotation: Rotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let bytes = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            rotation,
+            writer: BufWriter::new(file),
+            bytes,
+            opened: Instant::now(),
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let too_big = match self.rotation.max_bytes {
+            Some(max_bytes) => self.bytes + len > max_bytes,
+            None => false,
+        };
+        let too_old = match self.rotation.max_age {
+            Some(max_age) => self.opened.elapsed() >= max_age,
+            None => false,
+        };
+        if self.bytes > 0 && (too_big || too_old) {
+            self.rotate()?;
+        }
+        writeln!(self.writer, "{}", line)?;
+        self.bytes += len;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Shift the rotated files up by one, then start a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let mut count = 0;
+        while rotated_path(&self.path, count + 1).exists() {
+            count += 1;
+        }
+        if let Some(max_files) = self.rotation.max_files {
+            while count >= max_files && count > 0 {
+                fs::remove_file(rotated_path(&self.path, count))?;
+                count -= 1;
+            }
+        }
+        for n in (1..=count).rev() {
+            fs::rename(rotated_path(&self.path, n), rotated_path(&self.path, n + 1))?;
+        }
+        if self.rotation.max_files == Some(0) {
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.bytes = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+/// `path` with `.n` appended, e.g. `spans.jsonl.2`
+pub fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
+
+fn encode(service_name: &str, span: &FinishedSpan) -> String {
+    let state = span.context().state();
+    let references: Vec<Value> = span
+        .references()
+        .iter()
+        .map(|reference| {
+            let ref_type = match reference {
+                SpanReference::ChildOf(_) => "child_of",
+                SpanReference::FollowsFrom(_) => "follows_from",
+            };
+            json!({
+                "type": ref_type,
+                "trace_id": reference.span().trace_id().to_string(),
+                "span_id": format!("{:x}", reference.span().span_id()),
+            })
+        })
+        .collect();
+    let tags: Vec<Value> = span.tags().iter().map(tag_json).collect();
+    let logs: Vec<Value> = span
+        .logs()
+        .iter()
+        .map(|log| {
+            let fields: Vec<Value> = log
+                .fields()
+                .iter()
+                .map(|f| json!({"key": f.name(), "value": f.value()}))
+                .collect();
+            json!({"time_ns": epoch_nanos(log.time()), "fields": fields})
+        })
+        .collect();
+    json!({
+        "service": service_name,
+        "trace_id": state.trace_id().to_string(),
+        "span_id": format!("{:x}", state.span_id()),
+        "operation": span.operation_name(),
+        "start_ns": epoch_nanos(span.start_time()),
+        "finish_ns": epoch_nanos(span.finish_time()),
+        "references": references,
+        "tags": tags,
+        "logs": logs,
+    })
+    .to_string()
+}
+
+fn tag_json(tag: &Tag) -> Value {
+    match tag.value() {
+        TagValue::String(s) => json!({"key": tag.name(), "value": s.as_ref()}),
+        TagValue::Boolean(b) => json!({"key": tag.name(), "value": b}),
+        TagValue::Integer(i) => json!({"key": tag.name(), "value": i}),
+        TagValue::Float(f) if f.is_finite() => json!({"key": tag.name(), "value": f}),
+        TagValue::Float(f) => json!({"key": tag.name(), "value": f.to_string(), "type": "float"}),
+    }
+}
+
+fn decode_tag(tag: &Value) -> Result<Tag, String> {
+    let key = field_str(tag, "key")?.to_owned();
+    let value = match tag["type"].as_str() {
+        Some("float") => {
+            let value = field_str(tag, "value")?;
+            let float: f64 = value
+                .parse()
+                .map_err(|_| format!("{:?} is not a float", value))?;
+            TagValue::from(float)
+        }
+        Some(other) => return Err(format!("unknown tag type {:?}", other)),
+        None => tag_value(&tag["value"])?,
+    };
+    Ok(Tag::new(key, value))
+}
+
+fn epoch_nanos(time: SystemTime) -> u64 {
+    let since = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    since.as_secs() * 1_000_000_000 + u64::from(since.subsec_nanos())
+}
+
+/// The tag `RecordedSpan::with_service_tag` adds
+pub const SERVICE_TAG: &str = "service";
+
+/// A span read back from a file, with the service which recorded it
+#[derive(Debug)]
+pub struct RecordedSpan {
+    pub service: String,
+    pub span: FinishedSpan,
+}
+
+impl RecordedSpan {
+    /// The span with its service as a `SERVICE_TAG` tag, unless it already
+    /// has one, e.g. to tell services apart once their spans are merged
+    pub fn with_service_tag(self) -> FinishedSpan {
+        let tagged = self.span.tags().iter().any(|t| t.name() == SERVICE_TAG);
+        if tagged || self.service.is_empty() {
+            return self.span;
+        }
+        let mut parts = SpanParts::of(&self.span);
+        parts.tags.push(Tag::new(SERVICE_TAG, self.service));
+        Rebuilder::new().build(parts)
+    }
+}
+
+/// Read the spans written by a file reporter. Blank lines are skipped, and any
+/// other line which isn't a recorded span is an `InvalidData` error naming it.
+pub fn read_spans<R: BufRead>(reader: R) -> io::Result<Vec<RecordedSpan>> {
+    let rebuilder = Rebuilder::new();
+    let mut spans = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let span = serde_json::from_str(&line)
+            .map_err(|e| e.to_string())
+            .and_then(|value| decode(&rebuilder, &value))
+            .map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", i + 1, e))
+            })?;
+        spans.push(span);
+    }
+    Ok(spans)
+}
+
+/// Read the spans from `path` and the files rotated out of it, oldest first
+pub fn read_rotated<P: AsRef<Path>>(path: P) -> io::Result<Vec<RecordedSpan>> {
+    let path = path.as_ref();
+    let mut count = 0;
+    while rotated_path(path, count + 1).exists() {
+        count += 1;
+    }
+    let mut spans = Vec::new();
+    for n in (1..=count).rev() {
+        spans.extend(read_file(&rotated_path(path, n))?);
+    }
+    spans.extend(read_file(path)?);
+    Ok(spans)
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<RecordedSpan>> {
+    read_spans(io::BufReader::new(File::open(path)?))
+}
+
+fn decode(rebuilder: &Rebuilder, value: &Value) -> Result<RecordedSpan, String> {
+    let references = array(value, "references")
+        .iter()
+        .map(|reference| {
+            let child_of = match reference["type"].as_str() {
+                Some("child_of") => true,
+                Some("follows_from") => false,
+                _ => return Err(format!("unknown reference type {}", reference["type"])),
+            };
+            Ok((child_of, context_state(reference, "trace_id", "span_id")?))
+        })
+        .collect::<Result<_, String>>()?;
+    let tags = array(value, "tags")
+        .iter()
+        .map(decode_tag)
+        .collect::<Result<_, String>>()?;
+    let logs = array(value, "logs")
+        .iter()
+        .map(|log| {
+            let fields = array(log, "fields")
+                .iter()
+                .map(|field| {
+                    Ok(LogField::new(
+                        field_str(field, "key")?.to_owned(),
+                        field_str(field, "value")?.to_owned(),
+                    ))
+                })
+                .collect::<Result<_, String>>()?;
+            Ok((field_time(log, "time_ns")?, fields))
+        })
+        .collect::<Result<_, String>>()?;
+    let span = rebuilder.build(SpanParts {
+        state: context_state(value, "trace_id", "span_id")?,
+        operation: field_str(value, "operation")?.to_owned(),
+        start: field_time(value, "start_ns")?,
+        finish: field_time(value, "finish_ns")?,
+        references,
+        tags,
+        logs,
+    });
+    Ok(RecordedSpan {
+        service: value["service"].as_str().unwrap_or_default().to_owned(),
+        span,
+    })
+}
+
+fn field_time(value: &Value, key: &str) -> Result<SystemTime, String> {
+    value[key]
+        .as_u64()
+        .map(|nanos| SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos))
+        .ok_or_else(|| format!("missing time field {:?}", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer_console::ConsoleReporter;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("holochain_tracing_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn record(path: &Path, rotation: Rotation, spans: usize) {
+        let (tracer, reporter) = new_tracer_with_file_reporter("test", path, rotation).unwrap();
+        {
+            let root: crate::Span = tracer.span("root").start().into();
+            for _ in 1..spans {
+                let _child = root.child("child");
+            }
+        }
+        drop(tracer);
+        reporter.join().unwrap();
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let path = temp_dir("round_trip").join("spans.jsonl");
+        let (tracer, reporter) =
+            new_tracer_with_file_reporter("alice", &path, Rotation::never()).unwrap();
+        let (console_tx, console_rx) = crossbeam_channel::unbounded();
+        let console_tracer = Tracer::with_sender(AllSampler, console_tx);
+        let nan: f64 = "NaN".parse().unwrap();
+        let neg_inf: f64 = "-inf".parse().unwrap();
+        for tracer in [&tracer, &console_tracer].iter() {
+            let parent: crate::Span = tracer.span("parent").start().into();
+            let mut child = parent.child("child");
+            child.set_tag(|| Tag::new("b", "text"));
+            child.set_tag(|| Tag::new("a", 2));
+            child.set_tag(|| Tag::new("ok", true));
+            child.set_tag(|| Tag::new("ratio", 0.5));
+            child.set_tag(|| Tag::new("nan", nan));
+            child.set_tag(|| Tag::new("inf", neg_inf));
+            child.event("hello");
+            drop(child);
+            let _follower = parent.follower("follower");
+        }
+        drop((tracer, console_tracer));
+        reporter.join().unwrap();
+
+        let spans = read_spans(io::BufReader::new(File::open(&path).unwrap())).unwrap();
+        assert_eq!(spans.len(), 3);
+        assert!(spans.iter().all(|s| s.service == "alice"));
+        let child = spans
+            .iter()
+            .find(|s| s.span.operation_name() == "child")
+            .unwrap();
+        let mut tags: Vec<_> = child
+            .span
+            .tags()
+            .iter()
+            .map(|t| (t.name().to_owned(), t.value().clone()))
+            .collect();
+        // NaN isn't equal to itself
+        let (name, value) = tags.remove(4);
+        assert_eq!(name, "nan");
+        match value {
+            TagValue::Float(f) => assert!(f.is_nan()),
+            other => panic!("NaN read back as {:?}", other),
+        }
+        assert_eq!(
+            tags,
+            vec![
+                ("b".to_owned(), TagValue::from("text")),
+                ("a".to_owned(), TagValue::from(2)),
+                ("ok".to_owned(), TagValue::from(true)),
+                ("ratio".to_owned(), TagValue::from(0.5)),
+                ("inf".to_owned(), TagValue::from(neg_inf)),
+            ]
+        );
+        // Read back, the spans render just like the ones never written out
+        let mut recorded = ConsoleReporter::new({
+            let (span_tx, span_rx) = crossbeam_channel::unbounded();
+            for span in spans {
+                span_tx.send(span.span).unwrap();
+            }
+            span_rx
+        });
+        let mut live = ConsoleReporter::new(console_rx);
+        recorded.drain();
+        live.drain();
+        assert_eq!(
+            recorded.render_to_string(false),
+            live.render_to_string(false)
+        );
+
+        let error = read_spans(&b"\n{\"operation\": 1}\n"[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("line 2: "));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let path = temp_dir("rotation").join("spans.jsonl");
+        let lines = |path: &Path| fs::read_to_string(path).unwrap().lines().count();
+        record(&path, Rotation::never(), 2);
+        // Appends to an existing file
+        record(&path, Rotation::never(), 1);
+        assert_eq!(lines(&path), 3);
+        assert!(!rotated_path(&path, 1).exists());
+        // A span per file, keeping two old ones
+        let rotation = Rotation {
+            max_bytes: Some(1),
+            max_files: Some(2),
+            ..Default::default()
+        };
+        record(&path, rotation, 3);
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&rotated_path(&path, 1)), 1);
+        assert_eq!(lines(&rotated_path(&path, 2)), 1);
+        assert!(!rotated_path(&path, 3).exists());
+        let names: Vec<_> = read_rotated(&path)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.span.operation_name().to_owned())
+            .collect();
+        assert_eq!(names, vec!["child", "child", "root"]);
+
+        let aged = temp_dir("rotation_age").join("spans.jsonl");
+        let rotation = Rotation {
+            max_age: Some(Duration::from_secs(0)),
+            ..Default::default()
+        };
+        record(&aged, rotation, 3);
+        assert_eq!(lines(&aged), 1);
+        assert_eq!(lines(&rotated_path(&aged, 2)), 1);
+        assert!(!rotated_path(&aged, 3).exists());
+        for path in [path, aged].iter() {
+            fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        }
+    }
+}
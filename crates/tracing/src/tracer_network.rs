@@ -1,7 +1,10 @@
 use crate::{reporter, Tracer};
 use std::thread;
 
-pub use rustracing::{sampler::*, span::SpanReceiver};
+pub use rustracing::{
+    sampler::*,
+    span::{SpanReceiver, SpanSender},
+};
 pub use rustracing_jaeger::{span::SpanContextState as RjSpanContextState, Span as RjSpan};
 
 fn run_reporter_thread(service_name: &'static str, span_rx: SpanReceiver<RjSpanContextState>) {
@@ -13,10 +16,15 @@ fn run_reporter_thread(service_name: &'static str, span_rx: SpanReceiver<RjSpanC
     });
 }
 
-/// Create a Tracer that sends all spans automatically to the default jaeger reporter
-pub fn new_tracer_with_network_reporter(service_name: &'static str) -> Tracer {
+/// A sender whose spans are sent to the default jaeger reporter, to compose with
+/// other sinks, e.g. `metrics::Metrics::forward`
+pub fn network_reporter_sender(service_name: &'static str) -> SpanSender<RjSpanContextState> {
     let (span_tx, span_rx) = crossbeam_channel::bounded(50);
     run_reporter_thread(service_name, span_rx);
-    let tracer = Tracer::with_sender(AllSampler, span_tx);
-    tracer
+    span_tx
+}
+
+/// Create a Tracer that sends all spans automatically to the default jaeger reporter
+pub fn new_tracer_with_network_reporter(service_name: &'static str) -> Tracer {
+    Tracer::with_sender(AllSampler, network_reporter_sender(service_name))
 }
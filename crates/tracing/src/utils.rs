@@ -1,7 +1,210 @@
+use crate::deterministic::start_span;
 use crate::{
-    push_span, stack::SpanStackGuard, with_top_or_null, EncodedSpanContext, Span, SpanContext,
-    SpanWrap, Tag, Tracer,
+    push_span, push_span_with, stack::SpanStackGuard, with_top_or_null, EncodedSpanContext, Span,
+    SpanContext, SpanWrap, Tag, Tracer,
 };
+use rustracing::sampler::Sampler;
+use rustracing::span::StartSpanOptions;
+use rustracing_jaeger::{span::SpanContextState, Span as RjSpan};
+use std::borrow::Cow;
+use std::time::SystemTime;
+
+/// How a new span relates to the span or context it is started from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Relation {
+    /// The new span is a child, i.e. the parent waits for it to finish
+    ChildOf,
+    /// The new span follows from the other, i.e. fire and forget
+    FollowsFrom,
+}
+
+/// Where a `SpanBuilder` finds the span to start from
+enum Parent {
+    /// The top of the span stack
+    Top,
+    /// An explicit context. None if the context was missing or failed to decode,
+    /// in which case no span is started.
+    Context(Option<SpanContext>),
+}
+
+/// Builds a new span from the top of the stack or from an incoming context,
+/// and either pushes it onto the stack or wraps some data with it.
+/// Spans follow from their parent unless `child` is called.
+/// # Example
+/// ```
+/// # use holochain_tracing::{null_tracer, Span, SpanBuilder, Tag};
+/// # let tracer = null_tracer();
+/// # let span = Span::from(tracer.span("Following from some incoming span wrap").start());
+/// # let span_wrap = span.wrap(1);
+/// let _spanguard = SpanBuilder::new("func_name")
+///     .tracer(&tracer)
+///     .wrapped(&span_wrap)
+///     .tag(Tag::new("a", 1))
+///     .tag(Tag::new("b", 2))
+///     .push();
+/// ```
+pub struct SpanBuilder<'a> {
+    name: Cow<'static, str>,
+    tracer: Option<&'a Tracer>,
+    parent: Parent,
+    relation: Relation,
+    tags: Vec<Tag>,
+    start_time: Option<SystemTime>,
+}
+
+impl<'a> SpanBuilder<'a> {
+    /// Constructor. Without a context, the span is started from the top of the stack.
+    pub fn new<N: Into<Cow<'static, str>>>(name: N) -> Self {
+        SpanBuilder {
+            name: name.into(),
+            tracer: None,
+            parent: Parent::Top,
+            relation: Relation::FollowsFrom,
+            tags: Vec::new(),
+            start_time: None,
+        }
+    }
+
+    /// The tracer to start spans from a context with. Not needed when starting
+    /// from the top of the stack.
+    pub fn tracer(mut self, tracer: &'a Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Start from a context, e.g. one received from another thread
+    pub fn context(mut self, context: &SpanContext) -> Self {
+        self.parent = Parent::Context(Some(context.clone()));
+        self
+    }
+
+    /// Start from an encoded context, e.g. one received from another process
+    pub fn encoded(mut self, context: &EncodedSpanContext) -> Self {
+        self.parent = Parent::Context(SpanContext::decode(context.clone()).ok());
+        self
+    }
+
+    /// Start from the context carried by a SpanWrap
+    pub fn wrapped<T>(mut self, span_wrap: &SpanWrap<T>) -> Self {
+        self.parent = Parent::Context(span_wrap.span_context.clone());
+        self
+    }
+
+    /// Add a tag
+    pub fn tag(mut self, tag: Tag) -> Self {
+        self.tags.push(tag);
+        self
+    }
+
+    /// Add several tags
+    pub fn tags<I: IntoIterator<Item = Tag>>(mut self, tags: I) -> Self {
+        self.tags.extend(tags);
+        self
+    }
+
+    /// Set the start time, which is otherwise the time the span is started
+    pub fn start_time(mut self, time: SystemTime) -> Self {
+        self.start_time = Some(time);
+        self
+    }
+
+    /// Set the relationship to the parent
+    pub fn relation(mut self, relation: Relation) -> Self {
+        self.relation = relation;
+        self
+    }
+
+    /// Start as a child of the parent
+    pub fn child(self) -> Self {
+        self.relation(Relation::ChildOf)
+    }
+
+    /// Start as a follower of the parent (the default)
+    pub fn follower(self) -> Self {
+        self.relation(Relation::FollowsFrom)
+    }
+
+    /// Start the span without putting it anywhere. Returns None if there is
+    /// nothing to start from: an empty stack, a missing or undecodable context,
+    /// or a context but no tracer.
+    pub fn start(self) -> Option<Span> {
+        match self.parent {
+            Parent::Top => crate::with_top(|top| self.start_from(top)),
+            Parent::Context(_) => self.start_from_context(),
+        }
+    }
+
+    /// Start the span and push it onto the stack
+    pub fn push(self) -> Option<SpanStackGuard> {
+        match self.parent {
+            Parent::Top => push_span_with(|top| self.start_from(top)),
+            Parent::Context(_) => self.start_from_context().map(push_span),
+        }
+    }
+
+    /// Start the span and wrap some data with its context. If there is nothing
+    /// to start from, the data is wrapped with a noop span.
+    pub fn wrap<T>(self, data: T) -> SpanWrap<T> {
+        let span = match self.parent {
+            Parent::Top => with_top_or_null(|top| self.start_from(top)),
+            Parent::Context(_) => self.start_from_context().unwrap_or_else(Span::noop),
+        };
+        span.wrap(data)
+    }
+
+    fn start_from(self, top: &mut Span) -> Span {
+        let SpanBuilder {
+            name,
+            relation,
+            tags,
+            start_time,
+            ..
+        } = self;
+        let parent = top.0.context().cloned();
+        let parent = parent.as_ref();
+        match relation {
+            Relation::ChildOf => top.child_(name, |o| start_options(o, parent, tags, start_time)),
+            Relation::FollowsFrom => {
+                top.follower_(name, |o| start_options(o, parent, tags, start_time))
+            }
+        }
+        .into()
+    }
+
+    fn start_from_context(self) -> Option<Span> {
+        let SpanBuilder {
+            name,
+            tracer,
+            parent,
+            relation,
+            tags,
+            start_time,
+        } = self;
+        let context = match parent {
+            Parent::Context(Some(context)) => context,
+            _ => return None,
+        };
+        tracer.map(|t| {
+            let options = t.span(name);
+            let options = match relation {
+                Relation::ChildOf => options.child_of(&context.0),
+                Relation::FollowsFrom => options.follows_from(&context.0),
+            };
+            start_options(options, Some(&context.0), tags, start_time).into()
+        })
+    }
+}
+
+fn start_options<S: Sampler<SpanContextState>>(
+    options: StartSpanOptions<'_, S, SpanContextState>,
+    parent: Option<&rustracing::span::SpanContext<SpanContextState>>,
+    tags: Vec<Tag>,
+    start_time: Option<SystemTime>,
+) -> RjSpan {
+    let options = tags.into_iter().fold(options, |o, tag| o.tag(tag));
+    start_span(options, parent, start_time)
+}
+
 /// Add a span to the stack that follows from a SpanWrap
 /// # Example
 /// ```
@@ -20,72 +223,114 @@ pub fn follow<T>(
 ) -> Option<SpanStackGuard> {
     tracer
         .as_ref()
-        .map(|t| {
-            let root_span = span_wrap
-                .span_context
-                .as_ref()
-                .map(|c| c.follower(&t, name));
-            root_span.map(|span| push_span(span))
-        })
-        .flatten()
+        .and_then(|t| SpanBuilder::new(name).tracer(t).wrapped(span_wrap).push())
 }
 
+#[deprecated(note = "use SpanBuilder::encoded instead")]
 pub fn follow_encoded(
     tracer: &Option<Tracer>,
     span_context: &EncodedSpanContext,
     name: String,
 ) -> Option<SpanStackGuard> {
-    follow_encoded_tag_inner(tracer, span_context, name, None)
+    tracer.as_ref().and_then(|t| {
+        SpanBuilder::new(name)
+            .tracer(t)
+            .encoded(span_context)
+            .push()
+    })
 }
 
+#[deprecated(note = "use SpanBuilder::encoded and SpanBuilder::tag instead")]
 pub fn follow_encoded_tag(
     tracer: &Option<Tracer>,
     span_context: &EncodedSpanContext,
     name: String,
     tag: Tag,
-) -> Option<SpanStackGuard> {
-    follow_encoded_tag_inner(tracer, span_context, name, Some(tag))
-}
-
-fn follow_encoded_tag_inner(
-    tracer: &Option<Tracer>,
-    span_context: &EncodedSpanContext,
-    name: String,
-    tag: Option<Tag>,
 ) -> Option<SpanStackGuard> {
     tracer.as_ref().and_then(|t| {
-        let root_span = SpanContext::decode(span_context.clone()).ok().map(|c| {
-            c.follower_(&t, name, |options| {
-                if let Some(tag) = tag {
-                    options.tag(tag).start()
-                } else {
-                    options.start()
-                }
-            })
-        });
-        root_span.map(|span| push_span(span))
+        SpanBuilder::new(name)
+            .tracer(t)
+            .encoded(span_context)
+            .tag(tag)
+            .push()
     })
 }
 
 pub fn wrap<T>(data: T, name: String) -> SpanWrap<T> {
-    wrap_with_tag_inner(data, name, None)
+    SpanBuilder::new(name).wrap(data)
 }
 
+#[deprecated(note = "use SpanBuilder::tag and SpanBuilder::wrap instead")]
 pub fn wrap_with_tag<T>(data: T, name: String, tag: Tag) -> SpanWrap<T> {
-    wrap_with_tag_inner(data, name, Some(tag))
+    SpanBuilder::new(name).tag(tag).wrap(data)
 }
 
-fn wrap_with_tag_inner<T>(data: T, name: String, tag: Option<Tag>) -> SpanWrap<T> {
-    with_top_or_null(|top| {
-        let child: Span = top
-            .follower_(name, |options| {
-                if let Some(tag) = tag {
-                    options.tag(tag).start()
-                } else {
-                    options.start()
-                }
-            })
-            .into();
-        child.wrap(data)
-    })
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer_console::new_tracer_with_console_reporter;
+    use rustracing::tag::TagValue;
+    use std::time::Duration;
+
+    #[test]
+    fn test_builder_from_encoded_context() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        let root: Span = tracer.span("root").start().into();
+        let encoded = root.context().unwrap().encode().unwrap();
+        let start_time = SystemTime::now() - Duration::from_secs(1);
+        let span = SpanBuilder::new("remote")
+            .tracer(&tracer)
+            .encoded(&encoded)
+            .child()
+            .tags(vec![Tag::new("a", 1), Tag::new("b", 2)])
+            .start_time(start_time)
+            .start()
+            .unwrap();
+        assert_eq!(
+            span.context().unwrap().0.state().trace_id(),
+            root.context().unwrap().0.state().trace_id()
+        );
+        drop(span);
+        drop(root);
+        assert_eq!(reporter.drain(), 2);
+        let remote = reporter.query(&crate::query::SpanQuery::new().name("remote"));
+        assert_eq!(remote.len(), 1);
+        assert_eq!(remote[0].start_time(), start_time);
+        let tags: Vec<_> = remote[0]
+            .tags()
+            .iter()
+            .map(|t| (t.name().to_owned(), t.value().clone()))
+            .collect();
+        assert_eq!(
+            tags,
+            vec![
+                ("a".to_owned(), TagValue::from(1)),
+                ("b".to_owned(), TagValue::from(2)),
+            ]
+        );
+        assert!(SpanBuilder::new("no tracer")
+            .encoded(&encoded)
+            .start()
+            .is_none());
+        let no_context = SpanWrap::new((), None);
+        assert!(SpanBuilder::new("no context")
+            .tracer(&tracer)
+            .wrapped(&no_context)
+            .push()
+            .is_none());
+    }
+
+    #[test]
+    fn test_builder_from_top() {
+        let (tracer, mut reporter) = new_tracer_with_console_reporter();
+        assert!(SpanBuilder::new("empty stack").push().is_none());
+        let _g = push_span(tracer.span("root").start().into());
+        let wrapped = SpanBuilder::new("wrap").tag(Tag::new("k", "v")).wrap(5);
+        assert_eq!(*wrapped, 5);
+        assert!(wrapped.span_context.is_some());
+        {
+            let _child = SpanBuilder::new("child").child().push().unwrap();
+        }
+        assert_eq!(reporter.drain(), 2);
+    }
 }